@@ -1,5 +1,10 @@
 @group(0) @binding(1)
 var<uniform> aspectRatio: f32;
+// the first `nClusterColors` particles are colored by cluster instead of by type
+@group(0) @binding(2)
+var<uniform> nClusterColors: u32;
+@group(0) @binding(3)
+var<storage, read> clusterColors: array<u32>;
 
 struct VertexOutput {
     @location(0) color: vec3<f32>,
@@ -13,10 +18,15 @@ fn main_vs(
     @location(2) particle_col: vec3<f32>,
     @location(3) particle_type: f32,
     @location(4) position: vec2<f32>,
+    @builtin(instance_index) instanceIdx: u32,
 ) -> VertexOutput {
     let aspectMul = vec2<f32>(aspectRatio, 1.0);
     let screenPartPos = (particlePos * aspectMul * 2.0 - 1.0);
-    return VertexOutput(particle_col, vec4<f32>((position * aspectMul) + screenPartPos, 0.0, 1.0));
+    var color = particle_col;
+    if (instanceIdx < nClusterColors) {
+        color = unpack4x8unorm(clusterColors[instanceIdx]).rgb;
+    }
+    return VertexOutput(color, vec4<f32>((position * aspectMul) + screenPartPos, 0.0, 1.0));
 }
 
 @fragment
@@ -1,5 +1,10 @@
+// state of the previous step, only ever read
 @group(0) @binding(0)
-var<storage, read_write> particles: array<Particle>;
+var<storage, read> particles: array<Particle>;
+
+// state of the current step, only ever written
+@group(0) @binding(1)
+var<storage, read_write> particlesOut: array<Particle>;
 
 @group(1) @binding(0)
 var<uniform> settings: SettingsUniform;
@@ -7,6 +12,25 @@ var<uniform> settings: SettingsUniform;
 @group(1) @binding(1)
 var<storage, read> attractionTable: array<f32>;
 
+@group(1) @binding(2)
+var<storage, read> minRTable: array<f32>;
+
+@group(1) @binding(3)
+var<storage, read> maxRTable: array<f32>;
+
+@group(2) @binding(0)
+var<storage, read_write> cellCounts: array<atomic<u32>>;
+
+@group(2) @binding(1)
+var<storage, read_write> cellStarts: array<u32>;
+
+// (cell index, index within the cell) of every particle
+@group(2) @binding(2)
+var<storage, read_write> particleCells: array<vec2<u32>>;
+
+@group(2) @binding(3)
+var<storage, read_write> sortedIndices: array<u32>;
+
 
 struct SettingsUniform {
     deltaTime: f32,
@@ -16,17 +40,40 @@ struct SettingsUniform {
     nTypes: u32,
     nParticles: u32,
 
-    minR: f32,
     maxR: f32,
     friction: f32,
     speed: f32,
-    wrap: i32,
+    boundary: u32,
+
+    gridWidth: u32,
+    gridHeight: u32,
+
+    brushPos: vec2<f32>,
+    brushRadius: f32,
+    brushForce: f32,
 // #ifdef SIXTEEN_BYTE_ALIGNMENT
 //     _padding: vec3<f32>,
 // #endif
 }
 
 
+// must match `BoundaryMode` on the CPU side
+const BOUNDARY_PERIODIC: u32 = 0u;
+const BOUNDARY_REFLECTIVE: u32 = 1u;
+const BOUNDARY_SOFT_WALLS: u32 = 2u;
+const BOUNDARY_CLAMP: u32 = 3u;
+const BOUNDARY_CYLINDER: u32 = 4u;
+const BOUNDARY_CIRCLE: u32 = 5u;
+
+const PI: f32 = 3.14159265;
+const TAU: f32 = 6.28318531;
+
+const GRAVITY_SOFTENING: f32 = 0.05;
+
+const WALL_WIDTH: f32 = 0.05;
+const WALL_STRENGTH: f32 = 10.0;
+
+
 struct Particle {
     pos: vec2<f32>,
     vel: vec2<f32>,
@@ -42,57 +89,279 @@ fn getAttractionFactor(sourceIdx: u32, targetIdx: u32) -> f32 {
     return attractionTable[sourceIdx * settings.nTypes + targetIdx];
 }
 
-fn attraction(dst: f32, a: f32) -> f32 {
-    let r = dst / settings.maxR;
+fn getMinR(sourceIdx: u32, targetIdx: u32) -> f32 {
+    return minRTable[sourceIdx * settings.nTypes + targetIdx];
+}
+
+fn getMaxR(sourceIdx: u32, targetIdx: u32) -> f32 {
+    return maxRTable[sourceIdx * settings.nTypes + targetIdx];
+}
+
+// the force kernel is picked at pipeline creation through one of the FORCE_KERNEL_* shader defs,
+// `r` is the distance relative to maxR and `minR` is relative to maxR as well
 
-    if (r < settings.minR) {
-        return r / settings.minR - 1.0;
-    } else if (settings.minR < r && r < 1.0) {
-        return a * (1.0 - abs(2.0 * r - 1.0 - settings.minR) / (1.0 - settings.minR));
+#ifdef FORCE_KERNEL_LINEAR
+fn kernel(r: f32, a: f32, minR: f32) -> f32 {
+    if (r < minR) {
+        return r / minR - 1.0;
+    } else if (minR < r && r < 1.0) {
+        return a * (1.0 - abs(2.0 * r - 1.0 - minR) / (1.0 - minR));
     }
     return 0.0;
 }
+#endif
 
+#ifdef FORCE_KERNEL_SMOOTH
+fn kernel(r: f32, a: f32, minR: f32) -> f32 {
+    if (r < minR) {
+        return -0.5 * (1.0 + cos(PI * r / minR));
+    } else if (minR < r && r < 1.0) {
+        return a * 0.5 * (1.0 - cos(TAU * (r - minR) / (1.0 - minR)));
+    }
+    return 0.0;
+}
+#endif
+
+#ifdef FORCE_KERNEL_LENNARD_JONES
+fn kernel(r: f32, a: f32, minR: f32) -> f32 {
+    // the repulsive term always wins close in, `a` scales the attracting tail,
+    // which is tapered off towards the cutoff
+    let x6 = pow(minR / max(r, 0.001), 6.0);
+    return clamp(a * x6 - 2.0 * x6 * x6, -1.0, 1.0) * (1.0 - r);
+}
+#endif
+
+#ifdef FORCE_KERNEL_GRAVITY
+fn kernel(r: f32, a: f32, minR: f32) -> f32 {
+    if (r < minR) {
+        return r / minR - 1.0;
+    }
+    // softened so that the force stays bounded right outside the repulsion zone
+    return a * GRAVITY_SOFTENING / (r * r + GRAVITY_SOFTENING);
+}
+#endif
+
+#ifdef FORCE_KERNEL_RINGS
+fn kernel(r: f32, a: f32, minR: f32) -> f32 {
+    if (r < minR) {
+        return r / minR - 1.0;
+    } else if (minR < r && r < 1.0) {
+        // alternating attracting and repelling bands
+        return a * sin(3.0 * PI * (r - minR) / (1.0 - minR));
+    }
+    return 0.0;
+}
+#endif
+
+fn attraction(dst: f32, a: f32, minR: f32, maxR: f32) -> f32 {
+    return kernel(dst / maxR, a, minR);
+}
+
+
+fn worldExtent() -> vec2<f32> {
+    return vec2(settings.invAspectRatio, 1.0);
+}
+
+// axes along which the world wraps around onto itself
+fn wrapAxes() -> vec2<bool> {
+    let periodic = settings.boundary == BOUNDARY_PERIODIC;
+    return vec2(periodic || settings.boundary == BOUNDARY_CYLINDER, periodic);
+}
+
+// maps positions back into [0, extent) on the wrapping axes, however far they left the world,
+// the same way the minimum image convention treats distances
+fn wrapPosition(pos: vec2<f32>, axes: vec2<bool>) -> vec2<f32> {
+    let extent = worldExtent();
+    var wrapped = pos - extent * floor(pos / extent);
+    // tiny negative positions can round up to exactly the extent
+    wrapped = select(wrapped, vec2(0.0), wrapped >= extent);
+    return select(pos, wrapped, axes);
+}
+
+// pushes particles away from the edges, strongest right at the edge
+fn wallForce(pos: vec2<f32>) -> vec2<f32> {
+    let fromLow = clamp(1.0 - pos / WALL_WIDTH, vec2(0.0), vec2(1.0));
+    let fromHigh = clamp(1.0 - (worldExtent() - pos) / WALL_WIDTH, vec2(0.0), vec2(1.0));
+    return (fromLow - fromHigh) * WALL_STRENGTH;
+}
+
+fn cellCoord(pos: vec2<f32>) -> vec2<i32> {
+    let gridSize = vec2<i32>(i32(settings.gridWidth), i32(settings.gridHeight));
+    let uv = pos / vec2(settings.invAspectRatio, 1.0);
+    return clamp(vec2<i32>(floor(uv * vec2<f32>(gridSize))), vec2(0), gridSize - 1);
+}
+
+fn cellIndex(coord: vec2<i32>) -> u32 {
+    return u32(coord.y) * settings.gridWidth + u32(coord.x);
+}
+
+
+@compute @workgroup_size(64, 1, 1)
+fn clear_grid(@builtin(global_invocation_id) id: vec3<u32>) {
+    if (id.x >= settings.gridWidth * settings.gridHeight) {
+        return;
+    }
+    atomicStore(&cellCounts[id.x], 0u);
+}
+
+@compute @workgroup_size(64, 1, 1)
+fn bin_particles(@builtin(global_invocation_id) id: vec3<u32>) {
+    if (id.x >= settings.nParticles) {
+        return;
+    }
+    let cell = cellIndex(cellCoord(particles[id.x].pos));
+    let idxInCell = atomicAdd(&cellCounts[cell], 1u);
+    particleCells[id.x] = vec2(cell, idxInCell);
+}
+
+var<workgroup> scanSums: array<u32, 256>;
+
+// exclusive prefix sum of cellCounts into cellStarts, run as a single workgroup
+@compute @workgroup_size(256, 1, 1)
+fn prefix_sum(@builtin(local_invocation_index) idx: u32) {
+    let nCells = settings.gridWidth * settings.gridHeight;
+    let chunkSize = (nCells + 255u) / 256u;
+    let chunkStart = idx * chunkSize;
+    let chunkEnd = min(chunkStart + chunkSize, nCells);
+
+    var chunkSum = 0u;
+    for (var c = chunkStart; c < chunkEnd; c++) {
+        chunkSum += atomicLoad(&cellCounts[c]);
+    }
+    scanSums[idx] = chunkSum;
+    workgroupBarrier();
+
+    for (var offset = 1u; offset < 256u; offset <<= 1u) {
+        var sum = scanSums[idx];
+        if (idx >= offset) {
+            sum += scanSums[idx - offset];
+        }
+        workgroupBarrier();
+        scanSums[idx] = sum;
+        workgroupBarrier();
+    }
+
+    var start = scanSums[idx] - chunkSum;
+    for (var c = chunkStart; c < chunkEnd; c++) {
+        cellStarts[c] = start;
+        start += atomicLoad(&cellCounts[c]);
+    }
+}
+
+@compute @workgroup_size(64, 1, 1)
+fn sort_particles(@builtin(global_invocation_id) id: vec3<u32>) {
+    if (id.x >= settings.nParticles) {
+        return;
+    }
+    let cell = particleCells[id.x];
+    sortedIndices[cellStarts[cell.x] + cell.y] = id.x;
+}
+
+// atomics make the order within a cell arbitrary, so replace each particle's slot by its
+// rank among the particles of its cell by index, for `sort_particles` to run again with.
+// This keeps the force summation order, and with it the simulation, deterministic. Each
+// particle reads its own cell once, less than `update` reads of the surrounding cells.
+@compute @workgroup_size(64, 1, 1)
+fn rank_particles(@builtin(global_invocation_id) id: vec3<u32>) {
+    if (id.x >= settings.nParticles) {
+        return;
+    }
+    let cell = particleCells[id.x].x;
+    let cellStart = cellStarts[cell];
+    let cellEnd = cellStart + atomicLoad(&cellCounts[cell]);
+    var rank = 0u;
+    for (var k = cellStart; k < cellEnd; k++) {
+        rank += u32(sortedIndices[k] < id.x);
+    }
+    particleCells[id.x] = vec2(cell, rank);
+}
 
 @compute @workgroup_size(64, 1, 1)
 fn update(@builtin(global_invocation_id) id: vec3<u32>) {
+    if (id.x >= settings.nParticles) {
+        return;
+    }
     let p = particles[id.x];
 
+    let gridSize = vec2<i32>(i32(settings.gridWidth), i32(settings.gridHeight));
+    let centerCell = cellCoord(p.pos);
+    let wrap = wrapAxes();
+    let extent = worldExtent();
+    // the neighboring cells, cut off at the edges along axes that do not wrap, and every
+    // cell along axes with fewer than three, where the neighborhood would visit a cell twice
+    let fewCells = gridSize < vec2(3);
+    let searchMin = select(select(max(centerCell - 1, vec2(0)), centerCell - 1, wrap), vec2(0), fewCells);
+    let searchMax = select(select(min(centerCell + 1, gridSize - 1), centerCell + 1, wrap), gridSize - 1, fewCells);
+
     var accel = vec2<f32>(0.0);
-    for (var i = 0u; i < settings.nParticles; i++) {
-        let targetPart = particles[i];
-        var dir = targetPart.pos - p.pos;
+    for (var cy = searchMin.y; cy <= searchMax.y; cy++) {
+        for (var cx = searchMin.x; cx <= searchMax.x; cx++) {
+            let coord = (vec2(cx, cy) + gridSize) % gridSize;
 
-        if (settings.wrap == 1) {
-            dir -= vec2(settings.invAspectRatio, 1.0) * round(dir / vec2(settings.invAspectRatio, 1.0));
-        }
+            let cell = cellIndex(coord);
+            let cellStart = cellStarts[cell];
+            let cellEnd = cellStart + atomicLoad(&cellCounts[cell]);
+            for (var k = cellStart; k < cellEnd; k++) {
+                let targetPart = particles[sortedIndices[k]];
+                var dir = targetPart.pos - p.pos;
+                dir -= select(vec2(0.0), extent * round(dir / extent), wrap);
 
-        let dst = length(dir);
+                let dst = length(dir);
+                let maxR = getMaxR(p.typeIdx, targetPart.typeIdx);
 
-        if (dst > 0.0 && dst < settings.maxR) {
-            let normDir = dir / dst;
+                if (dst > 0.0 && dst < maxR) {
+                    let normDir = dir / dst;
 
-            let attractionFactor = getAttractionFactor(p.typeIdx, targetPart.typeIdx);
-            let attractionAmount = attraction(dst, attractionFactor);
+                    let attractionFactor = getAttractionFactor(p.typeIdx, targetPart.typeIdx);
+                    let attractionAmount = attraction(dst, attractionFactor, getMinR(p.typeIdx, targetPart.typeIdx), maxR);
 
-            accel += normDir * attractionAmount;
+                    accel += normDir * attractionAmount * maxR;
+                }
+            }
+        }
+    }
+    accel *= settings.speed;
+    if (settings.boundary == BOUNDARY_SOFT_WALLS) {
+        accel += wallForce(p.pos);
+    }
+    if (settings.brushForce != 0.0) {
+        var toBrush = settings.brushPos - p.pos;
+        toBrush -= select(vec2(0.0), extent * round(toBrush / extent), wrap);
+        let brushDst = length(toBrush);
+        if (brushDst > 0.0 && brushDst < settings.brushRadius) {
+            accel += toBrush / brushDst * settings.brushForce * (1.0 - brushDst / settings.brushRadius);
         }
     }
-    accel *= settings.maxR * settings.speed;
 
-    let newVel = settings.friction * p.vel + accel * settings.deltaTime;
+    var newVel = settings.friction * p.vel + accel * settings.deltaTime;
     var newPos = p.pos + newVel * settings.deltaTime;
 
-    if (settings.wrap == 1) {
-        if (newPos.x >= settings.invAspectRatio || newPos.x < 0.0) {
-            newPos.x = abs(newPos.x - settings.invAspectRatio);
-        }
-        if (newPos.y >= 1.0 || newPos.y < 0.0) {
-            newPos.y = abs(newPos.y - 1.0);
+    newPos = wrapPosition(newPos, wrap);
+
+    if (settings.boundary == BOUNDARY_REFLECTIVE || settings.boundary == BOUNDARY_CYLINDER) {
+        let walls = !wrap;
+        let below = newPos < vec2(0.0);
+        let above = newPos > extent;
+        newPos = select(newPos, -newPos, below & walls);
+        newPos = select(newPos, 2.0 * extent - newPos, above & walls);
+        newVel = select(newVel, -newVel, (below | above) & walls);
+        // a single reflection is not enough for steps longer than the world
+        newPos = select(newPos, clamp(newPos, vec2(0.0), extent), walls);
+    } else if (settings.boundary == BOUNDARY_SOFT_WALLS || settings.boundary == BOUNDARY_CLAMP) {
+        let outside = (newPos < vec2(0.0)) | (newPos > extent);
+        newPos = clamp(newPos, vec2(0.0), extent);
+        newVel = select(newVel, vec2(0.0), outside);
+    } else if (settings.boundary == BOUNDARY_CIRCLE) {
+        let center = extent * 0.5;
+        let radius = 0.5;
+        let offset = newPos - center;
+        let dst = length(offset);
+        if (dst > radius) {
+            let normal = offset / dst;
+            newPos = center + normal * radius;
+            newVel -= 2.0 * max(dot(newVel, normal), 0.0) * normal;
         }
     }
 
-    storageBarrier();
-    particles[id.x].vel = newVel;
-    particles[id.x].pos = newPos;
+    particlesOut[id.x] = Particle(newPos, newVel, p.color, p.typeIdx);
 }
\ No newline at end of file
@@ -1,6 +1,6 @@
 mod particle_life;
 use bevy_egui::EguiPlugin;
-use particle_life::{*, ui::{ui_update, UIVisibility, UISettings, ui_render_update, ui_particles_update}};
+use particle_life::{*, ui::{ui_update, UIVisibility, UISettings, ui_render_update, ui_export_update, ui_particles_update, ui_force_plot_update, ui_trajectory_update, ui_stats_update}, brush::brush_input_update, spawn_image::{SpawnImageLoad, spawn_image_update}};
 
 
 #[allow(unused_imports)]
@@ -24,6 +24,7 @@ fn main() {
         .insert_resource(ClearColor(Color::BLACK))
         .init_resource::<UIVisibility>()
         .init_resource::<UISettings>()
+        .init_resource::<SpawnImageLoad>()
         .add_plugins((
             DefaultPlugins.set(WindowPlugin {
                 primary_window: Some(Window {
@@ -37,7 +38,9 @@ fn main() {
             ParticleLifeComputePlugin,
             EguiPlugin,
         ))
-        .add_systems(Update, (ui_update, ui_render_update, ui_particles_update))
+        .add_systems(Update, (ui_update, ui_render_update, ui_export_update, ui_particles_update, ui_force_plot_update, ui_trajectory_update, ui_stats_update))
+        .add_systems(Update, spawn_image_update.after(ui_particles_update))
+        .add_systems(Update, brush_input_update.after(ui_update).after(spawn_image_update))
         .run();
 }
 
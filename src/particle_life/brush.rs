@@ -0,0 +1,229 @@
+use std::sync::{Arc, Mutex};
+
+use bevy::{prelude::*, window::PrimaryWindow, render::{render_resource::{Buffer, BufferDescriptor, BufferUsages, MapMode}, renderer::{RenderDevice, RenderQueue}}};
+use bevy_egui::EguiContexts;
+use wgpu::Maintain;
+
+use super::{TEXTURE_SIZE, INIT_PARTICLE_CAPACITY, buffers::{Particle, ParticlesBuffer, particles_size, disk_offset}, texture::ParticleLifeOutputImageEntity, neighbors::world_extent, settings::SimulationClock, ui::{UISettings, BrushTool, BrushStroke}};
+
+
+/// Per type particle counts after the latest spawn or erase. Those edit the particle buffer
+/// in the render world, the resulting counts are handed back to the main world through here.
+#[derive(Resource, Clone, Default)]
+pub struct BrushCounts(Arc<Mutex<Option<Vec<u32>>>>);
+
+impl BrushCounts {
+    /// Replaces stale extracted counts until the main world has picked up the edited ones.
+    pub fn override_counts(&self, settings: &mut UISettings) {
+        let mut counts = self.0.lock().unwrap();
+        if let Some(edited) = counts.as_ref() {
+            if settings.particle_count_changed || settings.just_reset || settings.particles_per_type == *edited {
+                *counts = None;
+            } else {
+                settings.particles_per_type.clone_from(edited);
+            }
+        }
+    }
+}
+
+pub fn brush_input_update(
+    mut contexts: EguiContexts,
+    mut settings: ResMut<UISettings>,
+    brush_counts: Res<BrushCounts>,
+    mouse: Res<Input<MouseButton>>,
+    window_query: Query<&Window, With<PrimaryWindow>>,
+    camera_query: Query<(&Camera, &GlobalTransform)>,
+    out_img_query: Query<(&Sprite, &GlobalTransform), With<ParticleLifeOutputImageEntity>>,
+) {
+    // counts set through the UI this frame win over the edited ones
+    if !settings.particle_count_changed && !settings.just_reset {
+        if let Some(counts) = brush_counts.0.lock().unwrap().clone() {
+            settings.particles_per_type = counts;
+        }
+    }
+
+    settings.brush_stroke = None;
+    if settings.brush_tool == BrushTool::Off { return; }
+    let ctx = contexts.ctx_mut();
+    if ctx.wants_pointer_input() || ctx.is_pointer_over_area() { return; }
+
+    let secondary = match (mouse.pressed(MouseButton::Left), mouse.pressed(MouseButton::Right)) {
+        (true, _) => false,
+        (false, true) => true,
+        _ => return,
+    };
+    let (Ok(window), Ok((camera, camera_transform)), Ok((sprite, out_img_transform))) =
+        (window_query.get_single(), camera_query.get_single(), out_img_query.get_single()) else { return; };
+    let Some(world_pos) = window.cursor_position().and_then(|cursor| camera.viewport_to_world_2d(camera_transform, cursor)) else { return; };
+    let Some(size) = sprite.custom_size else { return; };
+
+    // the output image shows [0, 1 / aspect ratio) x [0, 1) of the simulation, y pointing up
+    let uv = (world_pos - out_img_transform.translation().truncate()) / size + 0.5;
+    if uv.cmplt(Vec2::ZERO).any() || uv.cmpge(Vec2::ONE).any() { return; }
+    let inv_aspect_ratio = TEXTURE_SIZE.0 as f32 / TEXTURE_SIZE.1 as f32;
+    settings.brush_stroke = Some(BrushStroke {
+        pos: [uv.x * inv_aspect_ratio, uv.y],
+        secondary,
+    });
+}
+
+enum EraseState {
+    Idle,
+    /// The node copies this many particles into the erase buffer this frame.
+    Copying(u32, BrushStroke),
+    /// Waiting for the copy to be mapped, the callback stores whether mapping succeeded.
+    Mapping(u32, BrushStroke, Arc<Mutex<Option<bool>>>),
+}
+
+/// Receives a copy of the latest particles to erase from, mapped without blocking the frame.
+#[derive(Resource)]
+pub struct EraseBuffer {
+    buffer: Buffer,
+    capacity: u32,
+    state: EraseState,
+}
+
+impl FromWorld for EraseBuffer {
+    fn from_world(world: &mut World) -> Self {
+        let device = world.resource::<RenderDevice>();
+        Self {
+            buffer: create_erase_buffer(device, INIT_PARTICLE_CAPACITY),
+            capacity: INIT_PARTICLE_CAPACITY,
+            state: EraseState::Idle,
+        }
+    }
+}
+
+impl EraseBuffer {
+    /// Buffer the node copies the latest particles into this frame and how many, if any.
+    pub fn copy_request(&self) -> Option<(&Buffer, u32)> {
+        match self.state {
+            EraseState::Copying(n_particles, _) => Some((&self.buffer, n_particles)),
+            _ => None,
+        }
+    }
+}
+
+fn create_erase_buffer(device: &RenderDevice, capacity: u32) -> Buffer {
+    device.create_buffer(&BufferDescriptor {
+        label: None,
+        size: particles_size(capacity),
+        usage: BufferUsages::MAP_READ | BufferUsages::COPY_DST,
+        mapped_at_creation: false,
+    })
+}
+
+/// Whether a particle is under the brush, across the edges of the world where it wraps.
+fn under_brush(p: &Particle, stroke: BrushStroke, radius: f32, wrap: BVec2) -> bool {
+    let extent = world_extent();
+    let delta = Vec2::from(p.pos) - Vec2::from(stroke.pos);
+    let delta = Vec2::select(wrap, delta - extent * (delta / extent).round(), delta);
+    delta.length_squared() < radius * radius
+}
+
+/// Spawns particles under the brush directly in the storage buffer holding the latest state.
+/// Erasing compacts a copy of that buffer once it is mapped, the simulation is held meanwhile.
+pub fn apply_brush_edits(
+    mut settings: ResMut<UISettings>,
+    particles_buf: Res<ParticlesBuffer>,
+    mut erase_buf: ResMut<EraseBuffer>,
+    mut clock: ResMut<SimulationClock>,
+    brush_counts: Res<BrushCounts>,
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+) {
+    let n_particles = settings.num_particles().min(particles_buf.capacity);
+    let storage = &particles_buf.storage[particles_buf.current];
+
+    let mut erased_counts = None;
+    if let EraseState::Mapping(n_copied, stroke, map_result) = &erase_buf.state {
+        render_device.wgpu_device().poll(Maintain::Poll);
+        let (n_copied, stroke) = (*n_copied, *stroke);
+        let Some(mapped) = *map_result.lock().unwrap() else {
+            clock.paused = true;
+            return;
+        };
+        if mapped {
+            // the particles may have been recreated or spawned since the copy
+            let unchanged = n_copied == n_particles && !settings.particle_count_changed && !settings.just_reset;
+            let slice = erase_buf.buffer.slice(..particles_size(n_copied));
+            let particles: Vec<Particle> = bytemuck::cast_slice(&slice.get_mapped_range()).to_vec();
+            erase_buf.buffer.unmap();
+            let wrap = settings.boundary.wrap_axes();
+            let kept: Vec<Particle> = particles.into_iter().filter(|p| !under_brush(p, stroke, settings.brush_radius, wrap)).collect();
+            if unchanged && kept.len() != n_copied as usize {
+                render_queue.write_buffer(storage, 0, bytemuck::cast_slice(&kept));
+                let mut counts = vec![0; settings.num_particle_types as usize];
+                for p in &kept {
+                    counts[p.type_idx as usize] += 1;
+                }
+                erased_counts = Some(counts);
+            }
+        }
+        erase_buf.state = EraseState::Idle;
+    }
+    clock.paused = false;
+
+    let counts = match erased_counts {
+        Some(counts) => counts,
+        None => {
+            let Some(stroke) = settings.brush_stroke else { return; };
+            // the particles were just recreated and the storage buffer does not hold them yet
+            if settings.particle_count_changed || settings.just_reset { return; }
+            match settings.brush_tool {
+                BrushTool::Spawn => {
+                    let type_idx = settings.brush_type.min(settings.num_particle_types - 1);
+                    let n_spawned = settings.brush_spawn_rate.min(particles_buf.capacity - n_particles);
+                    if n_spawned == 0 { return; }
+
+                    let mut rng = rand::thread_rng();
+                    let extent = world_extent();
+                    let spawned: Vec<Particle> = (0..n_spawned).map(|_| {
+                        let pos = Vec2::from(stroke.pos) + disk_offset(&mut rng, settings.brush_radius);
+                        Particle {
+                            pos: pos.rem_euclid(extent).into(),
+                            vel: [0.0, 0.0],
+                            color: settings.ptype_colors[type_idx as usize],
+                            type_idx,
+                        }
+                    }).collect();
+                    // appended after the live particles, the blocks of each type may interleave from here on
+                    render_queue.write_buffer(storage, particles_size(n_particles), bytemuck::cast_slice(&spawned));
+
+                    let mut counts = settings.particles_per_type.clone();
+                    counts[type_idx as usize] += n_spawned;
+                    counts
+                }
+                BrushTool::Erase => {
+                    if n_particles == 0 { return; }
+                    if erase_buf.capacity < n_particles {
+                        erase_buf.buffer = create_erase_buffer(&render_device, particles_buf.capacity);
+                        erase_buf.capacity = particles_buf.capacity;
+                    }
+                    // erased once the copy is mapped, in a later frame
+                    erase_buf.state = EraseState::Copying(n_particles, stroke);
+                    return;
+                }
+                _ => return,
+            }
+        }
+    };
+
+    settings.particles_per_type.clone_from(&counts);
+    *brush_counts.0.lock().unwrap() = Some(counts);
+}
+
+/// Maps the copy made by the node, after the frame's commands have been submitted.
+pub fn map_erase_buffer(
+    mut erase_buf: ResMut<EraseBuffer>,
+    render_device: Res<RenderDevice>,
+) {
+    let EraseState::Copying(n_particles, stroke) = erase_buf.state else { return; };
+    let map_result = Arc::new(Mutex::new(None));
+    let map_result_callback = map_result.clone();
+    let slice = erase_buf.buffer.slice(..particles_size(n_particles));
+    render_device.map_buffer(&slice, MapMode::Read, move |result| {
+        *map_result_callback.lock().unwrap() = Some(result.is_ok());
+    });
+    erase_buf.state = EraseState::Mapping(n_particles, stroke, map_result);
+}
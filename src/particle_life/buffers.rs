@@ -1,12 +1,14 @@
-use bevy::{prelude::*, render::{render_resource::{ShaderType, Buffer, BufferUsages, BufferInitDescriptor}, renderer::RenderDevice}};
+use bevy::{prelude::*, render::{render_resource::{ShaderType, Buffer, BufferUsages, BufferInitDescriptor, BufferDescriptor, CommandEncoderDescriptor, MapMode}, renderer::{RenderDevice, RenderQueue}}};
 use bytemuck::{Pod, Zeroable};
-use rand::Rng;
+use wgpu::Maintain;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
 
 // use crate::particle_life::TEXTURE_SIZE;
 
 use crate::particle_life::TEXTURE_SIZE;
 
-use super::{MAX_PARTICLES, INIT_NUM_TYPES, INIT_NUM_PARTICLES_PER_TYPE, ui::UISettings, MAX_PARTICLE_TYPES, INIT_PARTICLE_RADIUS};
+use super::{spawn_image::SpawnImage, INIT_NUM_TYPES, INIT_NUM_PARTICLES_PER_TYPE, ui::{UISettings, SpawnLayout}, INIT_PARTICLE_RADIUS, INIT_SEED, MAX_GRID_CELLS, INIT_PARTICLE_CAPACITY, MAX_PARTICLE_CAPACITY, WORKGROUP_SIZE};
 
 
 #[derive(Debug, Clone, Copy, Reflect, ShaderType, Pod, Zeroable)]
@@ -31,30 +33,24 @@ impl Particle {
 
 #[derive(Resource)]
 pub struct ParticlesBuffer {
-    pub storage: Buffer,
+    /// Ping-pong buffers, each step reads one and writes the other.
+    pub storage: [Buffer; 2],
+    /// Index of the storage buffer holding the latest particle state.
+    pub current: usize,
     pub staging: Buffer,
     pub vertex_data: Buffer,
     pub index_data: Buffer,
+    pub capacity: u32,
     pub size: u64,
 }
 
 impl FromWorld for ParticlesBuffer {
     fn from_world(world: &mut World) -> Self {
         let device = world.resource::<RenderDevice>();
-        let size = (MAX_PARTICLES * std::mem::size_of::<f32>() as u32 * 8) as u64;
-        let particles = create_particles(INIT_NUM_TYPES, INIT_NUM_PARTICLES_PER_TYPE);
+        let particles = create_particles(&[INIT_NUM_PARTICLES_PER_TYPE; INIT_NUM_TYPES as usize], INIT_PARTICLE_CAPACITY, INIT_SEED, SpawnLayout::default(), None);
         
-        let staging = device.create_buffer_with_data(&BufferInitDescriptor {
-            label: None,
-            contents: bytemuck::cast_slice(&particles),
-            usage: BufferUsages::MAP_READ | BufferUsages::COPY_DST | BufferUsages::VERTEX,
-        });
-
-        let storage = device.create_buffer_with_data(&BufferInitDescriptor {
-            label: None,
-            contents: bytemuck::cast_slice(&particles),
-            usage: BufferUsages::STORAGE | BufferUsages::COPY_DST | BufferUsages::COPY_SRC,
-        });
+        let staging = create_staging_buffer(device, &particles);
+        let storage = create_storage_buffers(device, &particles);
 
         let (vertices, indices) = create_hexagon_data(INIT_PARTICLE_RADIUS);
         let vertex_data = device.create_buffer_with_data(&BufferInitDescriptor {
@@ -71,21 +67,96 @@ impl FromWorld for ParticlesBuffer {
 
         Self {
             storage,
+            current: 0,
             staging,
             vertex_data,
             index_data,
+            capacity: INIT_PARTICLE_CAPACITY,
+            size: particles_size(INIT_PARTICLE_CAPACITY),
+        }
+    }
+}
+
+/// Buffers used to bin particles into a uniform grid for the neighbor search.
+#[derive(Resource)]
+pub struct GridBuffer {
+    pub cell_counts: Buffer,
+    pub cell_starts: Buffer,
+    pub particle_cells: Buffer,
+    pub sorted_indices: Buffer,
+    pub capacity: u32,
+}
+
+impl FromWorld for GridBuffer {
+    fn from_world(world: &mut World) -> Self {
+        let device = world.resource::<RenderDevice>();
+        Self::new(device, INIT_PARTICLE_CAPACITY)
+    }
+}
+
+impl GridBuffer {
+    fn new(device: &RenderDevice, capacity: u32) -> Self {
+        let create_buffer = |size: u64| device.create_buffer(&BufferDescriptor {
+            label: None,
             size,
+            usage: BufferUsages::STORAGE,
+            mapped_at_creation: false,
+        });
+        let u32_size = std::mem::size_of::<u32>() as u64;
+
+        Self {
+            cell_counts: create_buffer(MAX_GRID_CELLS as u64 * u32_size),
+            cell_starts: create_buffer(MAX_GRID_CELLS as u64 * u32_size),
+            particle_cells: create_buffer(capacity as u64 * u32_size * 2),
+            sorted_indices: create_buffer(capacity as u64 * u32_size),
+            capacity,
         }
     }
 }
 
-pub fn create_particle_colors(n_types: u32) -> [[f32; 3]; MAX_PARTICLE_TYPES as usize] {
+/// Rounds a particle count up to a valid capacity, a whole number of workgroups.
+pub fn aligned_capacity(n_particles: u32) -> u32 {
+    (n_particles.max(1).div_ceil(WORKGROUP_SIZE) * WORKGROUP_SIZE).min(MAX_PARTICLE_CAPACITY)
+}
+
+pub fn particles_size(n_particles: u32) -> u64 {
+    n_particles as u64 * std::mem::size_of::<Particle>() as u64
+}
+
+/// Blocks until the staging buffer, a copy of last frame's particles, is mapped and reads back
+/// the first `n_particles`.
+pub fn read_staging_particles(device: &RenderDevice, particles_buf: &ParticlesBuffer, n_particles: u32) -> Vec<Particle> {
+    let slice = particles_buf.staging.slice(..particles_size(n_particles));
+    device.map_buffer(&slice, MapMode::Read, |_| {});
+    device.wgpu_device().poll(Maintain::Wait);
+    let particles = bytemuck::cast_slice(&slice.get_mapped_range()).to_vec();
+    particles_buf.staging.unmap();
+    particles
+}
+
+fn create_staging_buffer(device: &RenderDevice, particles: &[Particle]) -> Buffer {
+    device.create_buffer_with_data(&BufferInitDescriptor {
+        label: None,
+        contents: bytemuck::cast_slice(particles),
+        usage: BufferUsages::MAP_READ | BufferUsages::COPY_DST | BufferUsages::VERTEX,
+    })
+}
+
+fn create_storage_buffers(device: &RenderDevice, particles: &[Particle]) -> [Buffer; 2] {
+    [(); 2].map(|_| device.create_buffer_with_data(&BufferInitDescriptor {
+        label: None,
+        contents: bytemuck::cast_slice(particles),
+        usage: BufferUsages::STORAGE | BufferUsages::COPY_DST | BufferUsages::COPY_SRC,
+    }))
+}
+
+pub fn create_particle_colors(n_types: u32) -> Vec<[f32; 3]> {
     const COLOR_A: Vec3 = Vec3::new(0.5, 0.5, 0.5);
     const COLOR_B: Vec3 = Vec3::new(0.5, 0.5, 0.5);
     const COLOR_C: Vec3 = Vec3::new(1.0, 1.0, 1.0);
     const COLOR_D: Vec3 = Vec3::new(0.0, 0.333, 0.667);
 
-    let mut colors = [[0.0; 3]; MAX_PARTICLE_TYPES as usize];
+    let mut colors = vec![[0.0; 3]; n_types as usize];
     for i in 0..n_types {
         let t = i as f32 / n_types as f32;
         let c1 = std::f32::consts::TAU * (COLOR_C * t + COLOR_D);
@@ -98,26 +169,78 @@ pub fn create_particle_colors(n_types: u32) -> [[f32; 3]; MAX_PARTICLE_TYPES as
     return colors;
 }
 
-fn create_particles(n_types: u32, n_per_type: u32) -> [Particle; MAX_PARTICLES as usize] {
-    let mut particles = [Particle::new(); MAX_PARTICLES as usize];
-    let mut rng = rand::thread_rng();
-    let colors = create_particle_colors(n_types);
+/// Lays out the particles of each type in a contiguous block, `counts[i]` particles of type `i`.
+/// The same seed always produces the same positions, on any machine and version of rand.
+fn create_particles(counts: &[u32], capacity: u32, seed: u64, layout: SpawnLayout, spawn_image: Option<&SpawnImage>) -> Vec<Particle> {
+    let mut particles = vec![Particle::new(); capacity as usize];
+    let mut rng = ChaCha8Rng::seed_from_u64(seed);
+    let colors = create_particle_colors(counts.len() as u32);
+    let n_types = counts.len() as u32;
 
-    for i in 0..n_types {
-        let color = colors[i as usize];
-        for j in 0..n_per_type {
-            particles[(i * n_per_type + j) as usize] = Particle {
-                pos: [rng.gen_range(0f32..(TEXTURE_SIZE.0 as f32 / TEXTURE_SIZE.1 as f32)), rng.gen_range(0f32..1f32)],
+    let mut idx = 0;
+    for (i, &count) in counts.iter().enumerate() {
+        let color = colors[i];
+        for _ in 0..count {
+            if idx >= particles.len() {
+                break;
+            }
+            particles[idx] = Particle {
+                pos: spawn_position(layout, spawn_image, i as u32, n_types, &mut rng),
                 vel: [0.0, 0.0],
                 color,
-                type_idx: i,
+                type_idx: i as u32,
             };
+            idx += 1;
         }
     };
 
     return particles;
 }
 
+/// Initial position of a particle of type `type_idx`, wrapped into the world.
+fn spawn_position(layout: SpawnLayout, spawn_image: Option<&SpawnImage>, type_idx: u32, n_types: u32, rng: &mut impl Rng) -> [f32; 2] {
+    use std::f32::consts::TAU;
+
+    let extent = Vec2::new(TEXTURE_SIZE.0 as f32 / TEXTURE_SIZE.1 as f32, 1.0);
+    let center = extent * 0.5;
+    let type_frac = type_idx as f32 / n_types as f32;
+
+    let pos = match layout {
+        SpawnLayout::Image => match spawn_image.and_then(|image| image.sample(type_idx, rng)) {
+            Some(pos) => Vec2::from(pos),
+            None => Vec2::new(rng.gen_range(0f32..extent.x), rng.gen_range(0f32..extent.y)),
+        },
+        SpawnLayout::Uniform => Vec2::new(rng.gen_range(0f32..extent.x), rng.gen_range(0f32..extent.y)),
+        SpawnLayout::Clusters => {
+            let cluster_center = if n_types > 1 { center + Vec2::from_angle(TAU * type_frac) * 0.3 } else { center };
+            cluster_center + disk_offset(rng, 0.1)
+        }
+        SpawnLayout::Rings => {
+            let radius = 0.45 * (type_idx + 1) as f32 / n_types as f32;
+            center + Vec2::from_angle(rng.gen_range(0f32..TAU)) * radius + disk_offset(rng, 0.01)
+        }
+        SpawnLayout::Spiral => {
+            // one arm per type
+            let t = rng.gen::<f32>();
+            let angle = TAU * (2.0 * t + type_frac);
+            center + Vec2::from_angle(angle) * 0.45 * t + disk_offset(rng, 0.01)
+        }
+        SpawnLayout::Stripes => {
+            let stripe_width = extent.x / n_types as f32;
+            Vec2::new((type_idx as f32 + rng.gen::<f32>()) * stripe_width, rng.gen_range(0f32..extent.y))
+        }
+        SpawnLayout::Disk => center + disk_offset(rng, 0.4),
+        SpawnLayout::Blob => center + disk_offset(rng, 0.05),
+    };
+
+    [pos.x.rem_euclid(extent.x), pos.y.rem_euclid(extent.y)]
+}
+
+/// Uniformly distributed point in a disk around the origin.
+pub(super) fn disk_offset(rng: &mut impl Rng, radius: f32) -> Vec2 {
+    // sqrt for a uniform density over the area instead of bunching up in the middle
+    Vec2::from_angle(rng.gen_range(0f32..std::f32::consts::TAU)) * radius * rng.gen::<f32>().sqrt()
+}
 
 fn create_hexagon_data(mut radius: f32) -> ([f32; 12], [u32; 12]) {
     radius /= 100.0;
@@ -144,14 +267,51 @@ pub fn write_particles_buffer(
     mut particles_buf: ResMut<ParticlesBuffer>,
     ui_settings: Res<UISettings>,
     render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
 ) {
-    if ui_settings.particle_count_changed || ui_settings.just_reset {
-        let particles = create_particles(ui_settings.num_particle_types, ui_settings.num_particles_per_type);
-        particles_buf.storage = render_device.create_buffer_with_data(&BufferInitDescriptor {
-            label: None,
-            contents: bytemuck::cast_slice(&particles),
-            usage: BufferUsages::STORAGE | BufferUsages::COPY_DST | BufferUsages::COPY_SRC,
-        });
+    let capacity_changed = particles_buf.capacity != ui_settings.particle_capacity;
+    if ui_settings.particle_count_changed || ui_settings.just_reset || capacity_changed {
+        let particles = match &ui_settings.loaded_particles {
+            Some(loaded) => {
+                let mut particles = loaded.to_vec();
+                particles.resize(ui_settings.particle_capacity as usize, Particle::new());
+                particles
+            }
+            None => create_particles(&ui_settings.particles_per_type, ui_settings.particle_capacity, ui_settings.seed, ui_settings.spawn_layout, ui_settings.spawn_image.as_deref()),
+        };
+        let storage = create_storage_buffers(&render_device, &particles);
+        if !capacity_changed {
+            particles_buf.storage = storage;
+            return;
+        }
+        let staging = create_staging_buffer(&render_device, &particles);
+
+        // only resized, the running simulation carries over and new slots keep their seeded particles
+        let resized = !ui_settings.particle_count_changed && !ui_settings.just_reset && ui_settings.loaded_particles.is_none();
+        if resized {
+            let size = particles_size(particles_buf.capacity.min(ui_settings.particle_capacity));
+            let mut encoder = render_device.create_command_encoder(&CommandEncoderDescriptor::default());
+            for (old, new) in particles_buf.storage.iter().zip(&storage) {
+                encoder.copy_buffer_to_buffer(old, 0, new, 0, size);
+            }
+            encoder.copy_buffer_to_buffer(&particles_buf.storage[particles_buf.current], 0, &staging, 0, size);
+            render_queue.submit([encoder.finish()]);
+        }
+
+        particles_buf.storage = storage;
+        particles_buf.staging = staging;
+        particles_buf.capacity = ui_settings.particle_capacity;
+        particles_buf.size = particles_size(ui_settings.particle_capacity);
+    }
+}
+
+pub fn write_grid_buffer(
+    mut grid_buf: ResMut<GridBuffer>,
+    ui_settings: Res<UISettings>,
+    render_device: Res<RenderDevice>,
+) {
+    if grid_buf.capacity != ui_settings.particle_capacity {
+        *grid_buf = GridBuffer::new(&render_device, ui_settings.particle_capacity);
     }
 }
 
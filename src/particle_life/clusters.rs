@@ -0,0 +1,179 @@
+use std::sync::Arc;
+
+use bevy::{prelude::*, render::{render_resource::{StorageBuffer, UniformBuffer}, renderer::{RenderDevice, RenderQueue}}};
+
+use super::{readback::ParticleReadback, neighbors::NeighborGrid, ui::UISettings};
+
+
+/// Color of particles in clusters below `UISettings::min_cluster_size`.
+const UNCLUSTERED_COLOR: Color = Color::rgb(0.15, 0.15, 0.15);
+
+pub struct Cluster {
+    pub size: u32,
+    /// Number of particles of each type.
+    pub type_counts: Vec<u32>,
+}
+
+/// Connected components of the graph linking particles within `UISettings::neighbor_radius`
+/// of each other, as of the latest readback.
+#[derive(Resource, Default)]
+pub struct ParticleClusters {
+    /// Largest first, the index of a cluster is its ID.
+    pub clusters: Vec<Cluster>,
+    /// Particles in components below the minimum cluster size.
+    pub n_unclustered: u32,
+    /// Color of every particle of the readback by cluster ID, as linear RGBA8.
+    pub colors: Arc<Vec<u32>>,
+}
+
+/// Disjoint set forest over particle indices.
+struct UnionFind {
+    parents: Vec<u32>,
+    sizes: Vec<u32>,
+}
+
+impl UnionFind {
+    fn new(n: usize) -> Self {
+        Self {
+            parents: (0..n as u32).collect(),
+            sizes: vec![1; n],
+        }
+    }
+
+    fn find(&mut self, mut idx: u32) -> u32 {
+        while self.parents[idx as usize] != idx {
+            // path halving
+            let parent = self.parents[idx as usize];
+            self.parents[idx as usize] = self.parents[parent as usize];
+            idx = parent;
+        }
+        idx
+    }
+
+    fn union(&mut self, a: u32, b: u32) {
+        let (a, b) = (self.find(a), self.find(b));
+        if a == b { return; }
+        let (small, large) = if self.sizes[a as usize] < self.sizes[b as usize] { (a, b) } else { (b, a) };
+        self.parents[small as usize] = large;
+        self.sizes[large as usize] += self.sizes[small as usize];
+    }
+}
+
+/// Spreads consecutive IDs far apart in hue, so neighboring clusters are told apart.
+fn cluster_color(id: usize) -> Color {
+    const GOLDEN_RATIO_CONJUGATE: f32 = 0.618034;
+    Color::hsl((id as f32 * GOLDEN_RATIO_CONJUGATE).fract() * 360.0, 0.8, 0.6)
+}
+
+pub fn update_clusters(
+    mut clusters: ResMut<ParticleClusters>,
+    mut settings: ResMut<UISettings>,
+    readback: Res<ParticleReadback>,
+) {
+    if readback.is_changed() && !readback.is_added() {
+        let particles = &readback.particles;
+        let n_types = settings.num_particle_types as usize;
+
+        let grid = NeighborGrid::new(particles, settings.neighbor_radius, settings.boundary);
+        let mut components = UnionFind::new(particles.len());
+        for idx in 0..particles.len() {
+            grid.for_each_neighbor(particles, idx, |other| {
+                // every pair is visited from both sides
+                if other > idx {
+                    components.union(idx as u32, other as u32);
+                }
+            });
+        }
+
+        // number the components large enough to count as clusters by size
+        let mut roots: Vec<u32> = (0..particles.len() as u32)
+            .filter(|&idx| components.parents[idx as usize] == idx && components.sizes[idx as usize] >= settings.min_cluster_size)
+            .collect();
+        roots.sort_by_key(|&root| (std::cmp::Reverse(components.sizes[root as usize]), root));
+        let mut cluster_ids = vec![None; particles.len()];
+        let mut new_clusters: Vec<Cluster> = roots.iter().enumerate().map(|(id, &root)| {
+            cluster_ids[root as usize] = Some(id);
+            Cluster { size: components.sizes[root as usize], type_counts: vec![0; n_types] }
+        }).collect();
+
+        let mut colors = Vec::with_capacity(particles.len());
+        let mut n_unclustered = 0;
+        for (idx, p) in particles.iter().enumerate() {
+            let root = components.find(idx as u32);
+            let color = match cluster_ids[root as usize] {
+                Some(id) => {
+                    if let Some(count) = new_clusters[id].type_counts.get_mut(p.type_idx as usize) {
+                        *count += 1;
+                    }
+                    cluster_color(id)
+                }
+                None => {
+                    n_unclustered += 1;
+                    UNCLUSTERED_COLOR
+                }
+            };
+            colors.push(color.as_linear_rgba_u32());
+        }
+
+        clusters.clusters = new_clusters;
+        clusters.n_unclustered = n_unclustered;
+        clusters.colors = Arc::new(colors);
+    }
+
+    // the clusters are those of the simulation, not of the trajectory played back
+    let cluster_colors = (settings.color_by_cluster && settings.playback_frame.is_none()).then(|| clusters.colors.clone());
+    let unchanged = match (&settings.cluster_colors, &cluster_colors) {
+        (Some(old), Some(new)) => Arc::ptr_eq(old, new),
+        (old, new) => old.is_none() && new.is_none(),
+    };
+    if !unchanged {
+        settings.cluster_colors = cluster_colors;
+    }
+}
+
+/// Per particle colors drawn instead of the type colors while coloring by cluster.
+#[derive(Resource)]
+pub struct ClusterColorBuffer {
+    pub colors: StorageBuffer<Vec<u32>>,
+    /// Number of particles colored by cluster, zero colors every particle by type. The color
+    /// buffer can be longer than the latest readback and particles can be spawned since.
+    pub n_colors: UniformBuffer<u32>,
+    uploaded: Option<Arc<Vec<u32>>>,
+}
+
+impl FromWorld for ClusterColorBuffer {
+    fn from_world(world: &mut World) -> Self {
+        let device = world.resource::<RenderDevice>();
+        let queue = world.resource::<RenderQueue>();
+        // bindings cannot be empty, so there is always at least one color
+        let mut colors = StorageBuffer::from(vec![0u32]);
+        colors.write_buffer(device, queue);
+        Self {
+            colors,
+            n_colors: UniformBuffer::default(),
+            uploaded: None,
+        }
+    }
+}
+
+pub fn prepare_cluster_colors(
+    mut cluster_buf: ResMut<ClusterColorBuffer>,
+    settings: Res<UISettings>,
+    device: Res<RenderDevice>,
+    queue: Res<RenderQueue>,
+) {
+    let cluster_buf = &mut *cluster_buf;
+    let mut n_colors = 0;
+    if let Some(colors) = settings.cluster_colors.as_ref().filter(|colors| !colors.is_empty()) {
+        let uploaded = cluster_buf.uploaded.as_ref().is_some_and(|uploaded| Arc::ptr_eq(uploaded, colors));
+        if !uploaded {
+            cluster_buf.colors.get_mut().clone_from(colors);
+            cluster_buf.colors.write_buffer(&device, &queue);
+            cluster_buf.uploaded = Some(colors.clone());
+        }
+        n_colors = colors.len() as u32;
+    }
+
+    *cluster_buf.n_colors.get_mut() = n_colors;
+    cluster_buf.n_colors.write_buffer(&device, &queue);
+}
@@ -1,28 +1,35 @@
-use std::borrow::Cow;
+use std::{borrow::Cow, collections::HashMap};
 
-use bevy::{prelude::*, render::{render_resource::{BindGroup, BindGroupDescriptor, BindGroupEntry, BindGroupLayout, CachedComputePipelineId, BindGroupLayoutDescriptor, BindGroupLayoutEntry, ShaderStages, BindingType, TextureFormat, BufferBindingType, PipelineCache, ComputePipelineDescriptor, CachedPipelineState, ComputePassDescriptor, VertexState, VertexBufferLayout, VertexStepMode, VertexAttribute, VertexFormat, RenderPipelineDescriptor, FragmentState, PrimitiveState, MultisampleState, ColorTargetState, ColorWrites, CachedRenderPipelineId, RenderPassDescriptor, RenderPassColorAttachment, Operations, IndexFormat}, render_asset::RenderAssets, renderer::{RenderDevice, RenderContext}, render_graph, texture::BevyDefault}};
+use bevy::{prelude::*, render::{render_resource::{BindGroup, BindGroupDescriptor, BindGroupEntry, BindGroupLayout, CachedComputePipelineId, BindGroupLayoutDescriptor, BindGroupLayoutEntry, ShaderStages, BindingType, TextureFormat, BufferBindingType, PipelineCache, ComputePipelineDescriptor, CachedPipelineState, ComputePassDescriptor, VertexState, VertexBufferLayout, VertexStepMode, VertexAttribute, VertexFormat, RenderPipelineDescriptor, FragmentState, PrimitiveState, MultisampleState, ColorTargetState, ColorWrites, CachedRenderPipelineId, RenderPassDescriptor, RenderPassColorAttachment, Operations, IndexFormat, ShaderDefVal, ImageCopyBuffer, Extent3d, CommandEncoder, TextureView}, render_asset::RenderAssets, renderer::{RenderDevice, RenderContext}, render_graph, texture::BevyDefault}};
 
-use super::{MAX_PARTICLES, WORKGROUP_SIZE, texture::ParticleLifeImage, buffers::ParticlesBuffer, ui::UISettings, settings::SettingsBuffer};
+use super::{MAX_GRID_CELLS, WORKGROUP_SIZE, texture::ParticleLifeImage, buffers::{ParticlesBuffer, GridBuffer, particles_size}, ui::{UISettings, ForceKernel}, settings::{SettingsBuffer, SimulationClock}, readback::ReadbackBuffer, brush::EraseBuffer, export::{FrameExportBuffer, export_data_layout}, screenshot::ScreenshotTarget, clusters::ClusterColorBuffer, TEXTURE_SIZE};
 
 
+/// The first entry holds one particle bind group per ping-pong direction, indexed by the
+/// storage buffer that is read from.
 #[derive(Resource)]
-struct ParticleLifeBindGroups(BindGroup, BindGroup, BindGroup);
+struct ParticleLifeBindGroups([BindGroup; 2], BindGroup, BindGroup, BindGroup);
 
 pub fn queue_bind_group(
     mut commands: Commands,
     pipeline: Res<ParticleLifePipeline>,
     particle_life_particle_buf: Res<ParticlesBuffer>,
     particle_life_settings: Res<SettingsBuffer>,
+    particle_life_grid_buf: Res<GridBuffer>,
+    cluster_color_buf: Res<ClusterColorBuffer>,
     render_device: Res<RenderDevice>,
 ) {
-    let bind_group_buf = render_device.create_bind_group(&BindGroupDescriptor {
+    let bind_group_buf = [0usize, 1].map(|src| render_device.create_bind_group(&BindGroupDescriptor {
         label: None,
         layout: &pipeline.particle_buf_bind_group_layout,
         entries: &[BindGroupEntry {
             binding: 0,
-            resource: particle_life_particle_buf.storage.as_entire_binding(),
+            resource: particle_life_particle_buf.storage[src].as_entire_binding(),
+        }, BindGroupEntry {
+            binding: 1,
+            resource: particle_life_particle_buf.storage[1 - src].as_entire_binding(),
         }],
-    });
+    }));
     let bind_group_settings = render_device.create_bind_group(&BindGroupDescriptor {
         label: None,
         layout: &pipeline.settings_bind_group_layout,
@@ -32,6 +39,12 @@ pub fn queue_bind_group(
         }, BindGroupEntry {
             binding: 1,
             resource: particle_life_settings.attraction_tables.binding().unwrap(),
+        }, BindGroupEntry {
+            binding: 2,
+            resource: particle_life_settings.min_r_tables.binding().unwrap(),
+        }, BindGroupEntry {
+            binding: 3,
+            resource: particle_life_settings.max_r_tables.binding().unwrap(),
         }],
     });
     let bind_group_draw = render_device.create_bind_group(&BindGroupDescriptor {
@@ -40,17 +53,51 @@ pub fn queue_bind_group(
         entries: &[BindGroupEntry {
             binding: 1,
             resource: particle_life_settings.aspect_ratio.binding().unwrap(),
+        }, BindGroupEntry {
+            binding: 2,
+            resource: cluster_color_buf.n_colors.binding().unwrap(),
+        }, BindGroupEntry {
+            binding: 3,
+            resource: cluster_color_buf.colors.binding().unwrap(),
         }]
     });
-    commands.insert_resource(ParticleLifeBindGroups(bind_group_buf, bind_group_settings, bind_group_draw));
+    let bind_group_grid = render_device.create_bind_group(&BindGroupDescriptor {
+        label: None,
+        layout: &pipeline.grid_bind_group_layout,
+        entries: &[BindGroupEntry {
+            binding: 0,
+            resource: particle_life_grid_buf.cell_counts.as_entire_binding(),
+        }, BindGroupEntry {
+            binding: 1,
+            resource: particle_life_grid_buf.cell_starts.as_entire_binding(),
+        }, BindGroupEntry {
+            binding: 2,
+            resource: particle_life_grid_buf.particle_cells.as_entire_binding(),
+        }, BindGroupEntry {
+            binding: 3,
+            resource: particle_life_grid_buf.sorted_indices.as_entire_binding(),
+        }],
+    });
+    commands.insert_resource(ParticleLifeBindGroups(bind_group_buf, bind_group_settings, bind_group_draw, bind_group_grid));
 }
 
 #[derive(Resource)]
 pub struct ParticleLifePipeline {
     particle_buf_bind_group_layout: BindGroupLayout,
     settings_bind_group_layout: BindGroupLayout,
+    grid_bind_group_layout: BindGroupLayout,
     render_layout: BindGroupLayout,
+    compute_shader: Handle<Shader>,
+    /// Force kernel of `update_pipeline`, lags behind the UI while its pipeline compiles.
+    force_kernel: ForceKernel,
+    /// Update pipelines of every force kernel used so far, switching back to one is free.
+    update_pipelines: HashMap<ForceKernel, CachedComputePipelineId>,
     // init_pipeline: CachedComputePipelineId,
+    clear_grid_pipeline: CachedComputePipelineId,
+    bin_particles_pipeline: CachedComputePipelineId,
+    prefix_sum_pipeline: CachedComputePipelineId,
+    sort_particles_pipeline: CachedComputePipelineId,
+    rank_particles_pipeline: CachedComputePipelineId,
     update_pipeline: CachedComputePipelineId,
     render_pipeline: CachedRenderPipelineId,
 }
@@ -65,13 +112,23 @@ impl FromWorld for ParticleLifePipeline {
                     entries: &[BindGroupLayoutEntry {
                         binding: 0,
                         visibility: ShaderStages::COMPUTE,
+                        ty: BindingType::Buffer {
+                            ty: BufferBindingType::Storage {
+                                read_only: true,
+                            },
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    }, BindGroupLayoutEntry {
+                        binding: 1,
+                        visibility: ShaderStages::COMPUTE,
                         ty: BindingType::Buffer {
                             ty: BufferBindingType::Storage {
                                 read_only: false,
                             },
                             has_dynamic_offset: false,
                             min_binding_size: None,
-                            // min_binding_size: BufferSize::new((MAX_PARTICLES * std::mem::size_of::<f32>() as u32 * 8) as u64),
                         },
                         count: None,
                     }]
@@ -100,8 +157,47 @@ impl FromWorld for ParticleLifePipeline {
                             min_binding_size: None,
                         },
                         count: None,
+                    }, BindGroupLayoutEntry {
+                        binding: 2,
+                        visibility: ShaderStages::COMPUTE,
+                        ty: BindingType::Buffer {
+                            ty: BufferBindingType::Storage {
+                                read_only: true,
+                            },
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    }, BindGroupLayoutEntry {
+                        binding: 3,
+                        visibility: ShaderStages::COMPUTE,
+                        ty: BindingType::Buffer {
+                            ty: BufferBindingType::Storage {
+                                read_only: true,
+                            },
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
                     }]
                 });
+        let grid_bind_group_layout =
+            render_device
+                .create_bind_group_layout(&BindGroupLayoutDescriptor {
+                    label: None,
+                    entries: &(0..4).map(|binding| BindGroupLayoutEntry {
+                        binding,
+                        visibility: ShaderStages::COMPUTE,
+                        ty: BindingType::Buffer {
+                            ty: BufferBindingType::Storage {
+                                read_only: false,
+                            },
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    }).collect::<Vec<_>>(),
+                });
         let render_layout = render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
             label: None,
             entries: &[
@@ -123,6 +219,26 @@ impl FromWorld for ParticleLifePipeline {
                         min_binding_size: None,
                     },
                     count: None,
+                }, BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: ShaderStages::VERTEX,
+                    ty: BindingType::Buffer {
+                        ty: BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                }, BindGroupLayoutEntry {
+                    binding: 3,
+                    visibility: ShaderStages::VERTEX,
+                    ty: BindingType::Buffer {
+                        ty: BufferBindingType::Storage {
+                            read_only: true,
+                        },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
                 }
             ],
         });
@@ -185,29 +301,93 @@ impl FromWorld for ParticleLifePipeline {
             depth_stencil: None,
             multisample: MultisampleState::default(),
         });
-        let update_pipeline = pipeline_cache.queue_compute_pipeline(ComputePipelineDescriptor {
-            label: None,
-            layout: vec![particle_buf_bind_group_layout.clone(), settings_bind_group_layout.clone()],
-            push_constant_ranges: Vec::new(),
-            shader: compute_shader.clone(),
-            shader_defs: vec![],
-            entry_point: Cow::from("update"),
-        });
+        let compute_layout = vec![particle_buf_bind_group_layout.clone(), settings_bind_group_layout.clone(), grid_bind_group_layout.clone()];
+        let queue_compute_pipeline = |entry_point: &'static str| pipeline_cache.queue_compute_pipeline(
+            compute_pipeline_descriptor(compute_layout.clone(), compute_shader.clone(), entry_point, ForceKernel::default())
+        );
+        let clear_grid_pipeline = queue_compute_pipeline("clear_grid");
+        let bin_particles_pipeline = queue_compute_pipeline("bin_particles");
+        let prefix_sum_pipeline = queue_compute_pipeline("prefix_sum");
+        let sort_particles_pipeline = queue_compute_pipeline("sort_particles");
+        let rank_particles_pipeline = queue_compute_pipeline("rank_particles");
+        let update_pipeline = queue_compute_pipeline("update");
 
         ParticleLifePipeline {
             particle_buf_bind_group_layout,
             settings_bind_group_layout,
+            grid_bind_group_layout,
             render_layout,
+            compute_shader,
+            force_kernel: ForceKernel::default(),
+            update_pipelines: HashMap::from([(ForceKernel::default(), update_pipeline)]),
+            clear_grid_pipeline,
+            bin_particles_pipeline,
+            prefix_sum_pipeline,
+            sort_particles_pipeline,
+            rank_particles_pipeline,
             update_pipeline,
             render_pipeline,
         }
     }
 }
 
+impl ParticleLifePipeline {
+    fn compute_pipelines(&self) -> [CachedComputePipelineId; 6] {
+        [
+            self.clear_grid_pipeline,
+            self.bin_particles_pipeline,
+            self.prefix_sum_pipeline,
+            self.sort_particles_pipeline,
+            self.rank_particles_pipeline,
+            self.update_pipeline,
+        ]
+    }
+}
+
+/// The force kernel is compiled into the shader, so every kernel gets its own pipeline.
+fn compute_pipeline_descriptor(layout: Vec<BindGroupLayout>, shader: Handle<Shader>, entry_point: &'static str, force_kernel: ForceKernel) -> ComputePipelineDescriptor {
+    ComputePipelineDescriptor {
+        label: None,
+        layout,
+        push_constant_ranges: Vec::new(),
+        shader,
+        shader_defs: vec![ShaderDefVal::from(force_kernel.shader_def())],
+        entry_point: Cow::from(entry_point),
+    }
+}
+
+/// Keeps stepping with the previous force kernel until the pipeline of the selected one has
+/// compiled, so that switching kernels does not stall the simulation.
+pub fn queue_force_kernel(
+    mut pipeline: ResMut<ParticleLifePipeline>,
+    pipeline_cache: Res<PipelineCache>,
+    ui_settings: Res<UISettings>,
+) {
+    if pipeline.force_kernel == ui_settings.force_kernel {
+        return;
+    }
+    let force_kernel = ui_settings.force_kernel;
+    let update_pipeline = match pipeline.update_pipelines.get(&force_kernel) {
+        Some(id) => *id,
+        None => {
+            let layout = vec![pipeline.particle_buf_bind_group_layout.clone(), pipeline.settings_bind_group_layout.clone(), pipeline.grid_bind_group_layout.clone()];
+            let id = pipeline_cache.queue_compute_pipeline(compute_pipeline_descriptor(layout, pipeline.compute_shader.clone(), "update", force_kernel));
+            pipeline.update_pipelines.insert(force_kernel, id);
+            id
+        }
+    };
+    if let CachedPipelineState::Ok(_) = pipeline_cache.get_compute_pipeline_state(update_pipeline) {
+        pipeline.update_pipeline = update_pipeline;
+        pipeline.force_kernel = force_kernel;
+    }
+}
+
 enum ParticleLifeState {
     Init,
     Waiting,
     Update,
+    /// Compute is paused and a recorded trajectory frame is drawn from the staging buffer.
+    Playback,
 }
 
 pub struct ParticleLifeNode {
@@ -227,29 +407,53 @@ impl render_graph::Node for ParticleLifeNode {
         let pipeline = world.resource::<ParticleLifePipeline>();
         let pipeline_cache = world.resource::<PipelineCache>();
 
+        let pipelines_ready = pipeline.compute_pipelines().iter()
+            .all(|id| matches!(pipeline_cache.get_compute_pipeline_state(*id), CachedPipelineState::Ok(_)));
+        // pipelines are compiled again when the shader changes, wait for them like on startup
+        if !pipelines_ready {
+            self.state = ParticleLifeState::Init;
+        }
+
         match self.state {
             ParticleLifeState::Init => {
-                if let CachedPipelineState::Ok(_) =
-                    pipeline_cache.get_compute_pipeline_state(pipeline.update_pipeline)
-                {
+                if pipelines_ready {
                     self.state = ParticleLifeState::Waiting;
                 }
             }
             ParticleLifeState::Waiting => {
                 if let Some(ui_settings) = world.get_resource::<UISettings>() {
-                    if ui_settings.running {
+                    if ui_settings.playback_frame.is_some() {
+                        self.state = ParticleLifeState::Playback;
+                    } else if ui_settings.running {
                         self.state = ParticleLifeState::Update;
                     }
                 }
             }
             ParticleLifeState::Update => {
                 if let Some(ui_settings) = world.get_resource::<UISettings>() {
-                    if !ui_settings.running {
+                    if ui_settings.playback_frame.is_some() {
+                        self.state = ParticleLifeState::Playback;
+                    } else if !ui_settings.running {
+                        self.state = ParticleLifeState::Waiting;
+                    }
+                }
+            }
+            ParticleLifeState::Playback => {
+                if let Some(ui_settings) = world.get_resource::<UISettings>() {
+                    if ui_settings.playback_frame.is_none() {
                         self.state = ParticleLifeState::Waiting;
                     }
                 }
             }
         }
+
+        // every step run this frame writes into the other buffer, so after an odd number
+        // of steps the other buffer holds the latest state
+        if let ParticleLifeState::Update = self.state {
+            let steps = world.resource::<SimulationClock>().steps as usize;
+            let mut particles_buf = world.resource_mut::<ParticlesBuffer>();
+            particles_buf.current = (particles_buf.current + steps) % 2;
+        }
     }
 
     fn run(
@@ -258,69 +462,144 @@ impl render_graph::Node for ParticleLifeNode {
         render_context: &mut RenderContext,
         world: &World,
     ) -> Result<(), render_graph::NodeRunError> {
-        let particles_buf_bind_group = &world.resource::<ParticleLifeBindGroups>().0;
-        let settings_bind_group = &world.resource::<ParticleLifeBindGroups>().1;
-        let aspect_ratio_bind_group = &world.resource::<ParticleLifeBindGroups>().2;
         let particles_buf = &world.resource::<ParticlesBuffer>();
+        let particles_buf_bind_groups = &world.resource::<ParticleLifeBindGroups>().0;
+        let settings_bind_group = &world.resource::<ParticleLifeBindGroups>().1;
+        let grid_bind_group = &world.resource::<ParticleLifeBindGroups>().3;
         let pipeline_cache = world.resource::<PipelineCache>();
         let pipeline = world.resource::<ParticleLifePipeline>();
+        let ui_settings = world.resource::<UISettings>();
+        let n_particles = ui_settings.num_particles().min(particles_buf.capacity);
+        let n_playback = ui_settings.playback_frame.as_ref().map_or(0, |frame| frame.len() as u32).min(particles_buf.capacity);
+        let steps = world.resource::<SimulationClock>().steps as usize;
 
         let encoder = render_context.command_encoder();
         {
             let mut compute_pass = encoder.begin_compute_pass(&ComputePassDescriptor::default());
 
-            compute_pass.set_bind_group(0, particles_buf_bind_group, &[]);
             compute_pass.set_bind_group(1, settings_bind_group, &[]);
+            compute_pass.set_bind_group(2, grid_bind_group, &[]);
 
             match self.state {
                 ParticleLifeState::Init => {}
                 ParticleLifeState::Waiting => {}
+                ParticleLifeState::Playback => {}
                 ParticleLifeState::Update => {
-                    let update_particles_pipeline = pipeline_cache
-                        .get_compute_pipeline(pipeline.update_pipeline)
-                        .unwrap();
-                    compute_pass.set_pipeline(update_particles_pipeline);
-                    compute_pass.dispatch_workgroups(MAX_PARTICLES / WORKGROUP_SIZE, 1, 1);
+                    let particle_workgroups = n_particles.div_ceil(WORKGROUP_SIZE);
+                    let passes = [
+                        (pipeline.clear_grid_pipeline, MAX_GRID_CELLS / WORKGROUP_SIZE),
+                        (pipeline.bin_particles_pipeline, particle_workgroups),
+                        (pipeline.prefix_sum_pipeline, 1),
+                        (pipeline.sort_particles_pipeline, particle_workgroups),
+                        (pipeline.rank_particles_pipeline, particle_workgroups),
+                        // again, now by rank
+                        (pipeline.sort_particles_pipeline, particle_workgroups),
+                        (pipeline.update_pipeline, particle_workgroups),
+                    ];
+                    for step in 0..steps {
+                        // `current` is the buffer the last step writes, count back from there
+                        let src = (particles_buf.current + steps - step) % 2;
+                        compute_pass.set_bind_group(0, &particles_buf_bind_groups[src], &[]);
+                        for (pipeline_id, workgroups) in passes {
+                            let compute_pipeline = pipeline_cache
+                                .get_compute_pipeline(pipeline_id)
+                                .unwrap();
+                            compute_pass.set_pipeline(compute_pipeline);
+                            compute_pass.dispatch_workgroups(workgroups, 1, 1);
+                        }
+                    }
                 }
             }
         }
 
-        encoder.copy_buffer_to_buffer(&particles_buf.storage, 0, &particles_buf.staging, 0, particles_buf.size);
+        // during playback the staging buffer holds the recorded frame instead
+        let playback = matches!(self.state, ParticleLifeState::Playback);
+        if n_particles > 0 && !playback {
+            encoder.copy_buffer_to_buffer(&particles_buf.storage[particles_buf.current], 0, &particles_buf.staging, 0, particles_size(n_particles));
+        }
+        if let Some((readback, n_readback)) = world.resource::<ReadbackBuffer>().copy_request() {
+            encoder.copy_buffer_to_buffer(&particles_buf.storage[particles_buf.current], 0, readback, 0, particles_size(n_readback));
+        }
+        if let Some((erase, n_erase)) = world.resource::<EraseBuffer>().copy_request() {
+            encoder.copy_buffer_to_buffer(&particles_buf.storage[particles_buf.current], 0, erase, 0, particles_size(n_erase));
+        }
 
+        let n_drawn = match self.state {
+            ParticleLifeState::Update | ParticleLifeState::Waiting => n_particles,
+            ParticleLifeState::Playback => n_playback,
+            ParticleLifeState::Init => 0,
+        };
         {
             let gpu_images = world.resource::<RenderAssets<Image>>();
             let particle_life_image = world.resource::<ParticleLifeImage>();
             let view = &gpu_images[&particle_life_image.0];
-            let mut render_pass = encoder.begin_render_pass(&RenderPassDescriptor {
-                label: None,
-                color_attachments: &[Some(RenderPassColorAttachment {
-                    view: &view.texture_view,
-                    resolve_target: None,
-                    ops: Operations {
-                        load: Default::default(),
-                        store: true,
-                    },
-                })],
-                depth_stencil_attachment: None,
-            });
+            draw_particles(encoder, &view.texture_view, world, n_drawn);
+        }
 
-            render_pass.set_bind_group(0, &aspect_ratio_bind_group, &[]);
-            
-            match self.state {
-                ParticleLifeState::Update | ParticleLifeState::Waiting => {
-                    let render_pipeline = pipeline_cache
-                        .get_render_pipeline(pipeline.render_pipeline)
-                        .unwrap();
-                    render_pass.set_pipeline(render_pipeline);
-                    render_pass.set_vertex_buffer(0, *particles_buf.staging.slice(..));
-                    render_pass.set_vertex_buffer(1, *particles_buf.vertex_data.slice(..));
-                    render_pass.set_index_buffer(*particles_buf.index_data.slice(..), IndexFormat::Uint32);
-                    render_pass.draw_indexed(0..12, 0, 0..MAX_PARTICLES);
+        // the offscreen target only differs in resolution, as the particles are drawn in clip space
+        if let Some(screenshot) = &world.resource::<ScreenshotTarget>().0 {
+            draw_particles(encoder, &screenshot.view, world, n_drawn);
+            encoder.copy_texture_to_buffer(
+                screenshot.texture.as_image_copy(),
+                ImageCopyBuffer {
+                    buffer: &screenshot.buffer,
+                    layout: screenshot.data_layout(),
                 },
-                _ => ()
-            }
+                screenshot.size,
+            );
+        }
+
+        if let Some(export_buffer) = world.resource::<FrameExportBuffer>().copy_request() {
+            let gpu_images = world.resource::<RenderAssets<Image>>();
+            let view = &gpu_images[&world.resource::<ParticleLifeImage>().0];
+            encoder.copy_texture_to_buffer(
+                view.texture.as_image_copy(),
+                ImageCopyBuffer {
+                    buffer: export_buffer,
+                    layout: export_data_layout(),
+                },
+                Extent3d {
+                    width: TEXTURE_SIZE.0,
+                    height: TEXTURE_SIZE.1,
+                    depth_or_array_layers: 1,
+                },
+            );
         }
 
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+/// Clears `target` and draws the first `n_particles` of the staging buffer into it.
+fn draw_particles(encoder: &mut CommandEncoder, target: &TextureView, world: &World, n_particles: u32) {
+    let particles_buf = world.resource::<ParticlesBuffer>();
+    let aspect_ratio_bind_group = &world.resource::<ParticleLifeBindGroups>().2;
+    let pipeline_cache = world.resource::<PipelineCache>();
+    let pipeline = world.resource::<ParticleLifePipeline>();
+
+    let mut render_pass = encoder.begin_render_pass(&RenderPassDescriptor {
+        label: None,
+        color_attachments: &[Some(RenderPassColorAttachment {
+            view: target,
+            resolve_target: None,
+            ops: Operations {
+                load: Default::default(),
+                store: true,
+            },
+        })],
+        depth_stencil_attachment: None,
+    });
+
+    render_pass.set_bind_group(0, aspect_ratio_bind_group, &[]);
+
+    if n_particles > 0 {
+        let render_pipeline = pipeline_cache
+            .get_render_pipeline(pipeline.render_pipeline)
+            .unwrap();
+        render_pass.set_pipeline(render_pipeline);
+        render_pass.set_vertex_buffer(0, *particles_buf.staging.slice(..));
+        render_pass.set_vertex_buffer(1, *particles_buf.vertex_data.slice(..));
+        render_pass.set_index_buffer(*particles_buf.index_data.slice(..), IndexFormat::Uint32);
+        render_pass.draw_indexed(0..12, 0, 0..n_particles);
+    }
+}
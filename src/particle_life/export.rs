@@ -0,0 +1,244 @@
+use std::{fs::File, io::{self, BufWriter, Write}, path::{Path, PathBuf}, sync::{Arc, Mutex, mpsc::{self, SyncSender}}};
+
+use bevy::{prelude::*, tasks::IoTaskPool, render::{render_resource::{Buffer, BufferDescriptor, BufferUsages, MapMode, ImageDataLayout}, renderer::RenderDevice}};
+use wgpu::Maintain;
+
+use image::{Frame, RgbaImage, Delay, codecs::gif::{GifEncoder, Repeat}};
+
+use super::{ui::{UISettings, ExportFormat}, TEXTURE_SIZE};
+
+
+pub const DEFAULT_EXPORT_DIR: &str = "export";
+
+/// Rows of a texture to buffer copy must be aligned to this many bytes.
+const COPY_BYTES_PER_ROW_ALIGNMENT: u32 = 256;
+/// Frames waiting for the video writer before the render world blocks on it.
+const VIDEO_QUEUE_LEN: usize = 8;
+
+#[derive(Default)]
+pub struct ExportState {
+    pub frames_written: u32,
+    /// Set when a file could not be written, the main world then stops the export.
+    pub error: Option<String>,
+}
+
+/// State of the current export, shared with the main world for display.
+#[derive(Resource, Clone, Default)]
+pub struct ExportProgress(pub Arc<Mutex<ExportState>>);
+
+/// Receives a copy of the output image on every exported frame.
+#[derive(Resource)]
+pub struct FrameExportBuffer {
+    buffer: Buffer,
+    /// Set while the node should copy the output image this frame.
+    copy_requested: bool,
+    active: bool,
+    /// Set when the video writer could not be created, nothing is copied until the next export.
+    failed: bool,
+    frame: u32,
+    /// Frames for the video writer thread, dropping it finishes the file.
+    video: Option<SyncSender<Vec<u8>>>,
+}
+
+impl FromWorld for FrameExportBuffer {
+    fn from_world(world: &mut World) -> Self {
+        let device = world.resource::<RenderDevice>();
+        let buffer = device.create_buffer(&BufferDescriptor {
+            label: None,
+            size: padded_bytes_per_row(TEXTURE_SIZE.0) as u64 * TEXTURE_SIZE.1 as u64,
+            usage: BufferUsages::MAP_READ | BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        Self {
+            buffer,
+            copy_requested: false,
+            active: false,
+            failed: false,
+            frame: 0,
+            video: None,
+        }
+    }
+}
+
+impl FrameExportBuffer {
+    /// Buffer the node copies the output image into this frame, if any.
+    pub fn copy_request(&self) -> Option<&Buffer> {
+        self.copy_requested.then_some(&self.buffer)
+    }
+}
+
+pub(super) fn padded_bytes_per_row(width: u32) -> u32 {
+    (width * 4).div_ceil(COPY_BYTES_PER_ROW_ALIGNMENT) * COPY_BYTES_PER_ROW_ALIGNMENT
+}
+
+/// Layout of the output image in the export buffer.
+pub fn export_data_layout() -> ImageDataLayout {
+    ImageDataLayout {
+        offset: 0,
+        bytes_per_row: Some(padded_bytes_per_row(TEXTURE_SIZE.0)),
+        rows_per_image: None,
+    }
+}
+
+pub fn prepare_frame_export(
+    mut export_buf: ResMut<FrameExportBuffer>,
+    settings: Res<UISettings>,
+    progress: Res<ExportProgress>,
+) {
+    if settings.frame_export_active && !export_buf.active {
+        export_buf.frame = 0;
+        export_buf.failed = false;
+        *progress.0.lock().unwrap() = ExportState::default();
+        export_buf.video = match settings.export_format {
+            ExportFormat::PngSequence => None,
+            format => {
+                let path = Path::new(&settings.export_dir).join(format!("particle_life.{}", format.extension()));
+                match spawn_video_writer(format, &path, settings.export_fps, progress.clone()) {
+                    Ok(sender) => Some(sender),
+                    Err(err) => {
+                        progress.0.lock().unwrap().error = Some(format!("failed to create {}: {}", path.display(), err));
+                        export_buf.failed = true;
+                        None
+                    }
+                }
+            }
+        };
+    }
+    export_buf.active = settings.frame_export_active;
+    let frames_left = settings.export_frame_limit().is_none_or(|limit| export_buf.frame < limit);
+    export_buf.copy_requested = export_buf.active && !export_buf.failed && frames_left;
+    if !export_buf.copy_requested {
+        export_buf.video = None;
+    }
+}
+
+/// Reads back the frame copied by the node and writes it out as a numbered PNG or hands it
+/// to the video writer. Blocks until the copy is done, so that no frame is ever skipped
+/// however slow the scene is.
+pub fn write_exported_frame(
+    mut export_buf: ResMut<FrameExportBuffer>,
+    settings: Res<UISettings>,
+    progress: Res<ExportProgress>,
+    render_device: Res<RenderDevice>,
+) {
+    if !export_buf.copy_requested { return; }
+
+    let slice = export_buf.buffer.slice(..);
+    render_device.map_buffer(&slice, MapMode::Read, |_| {});
+    render_device.wgpu_device().poll(Maintain::Wait);
+    let padded_row = padded_bytes_per_row(TEXTURE_SIZE.0) as usize;
+    let row = TEXTURE_SIZE.0 as usize * 4;
+    let mut pixels = Vec::with_capacity(row * TEXTURE_SIZE.1 as usize);
+    for padded in slice.get_mapped_range().chunks_exact(padded_row) {
+        pixels.extend_from_slice(&padded[..row]);
+    }
+    export_buf.buffer.unmap();
+    // the output image is cleared to transparent, only the particles are opaque
+    pixels.chunks_exact_mut(4).for_each(|pixel| pixel[3] = 255);
+
+    let frame = export_buf.frame;
+    export_buf.frame += 1;
+    progress.0.lock().unwrap().frames_written = export_buf.frame;
+
+    if let Some(video) = &export_buf.video {
+        // blocks while the writer is behind, the writer thread only stops once the sender is dropped
+        let _ = video.send(pixels);
+        return;
+    }
+    if settings.export_format != ExportFormat::PngSequence { return; }
+    let path = PathBuf::from(&settings.export_dir).join(format!("frame_{:06}.png", frame));
+    let progress = progress.clone();
+    IoTaskPool::get().spawn(async move {
+        if let Some(dir) = path.parent() {
+            let _ = std::fs::create_dir_all(dir);
+        }
+        if let Err(err) = image::save_buffer(&path, &pixels, TEXTURE_SIZE.0, TEXTURE_SIZE.1, image::ColorType::Rgba8) {
+            progress.0.lock().unwrap().error = Some(format!("failed to write {}: {}", path.display(), err));
+        }
+    }).detach();
+}
+
+enum VideoEncoder {
+    Y4m(BufWriter<File>),
+    Gif(GifEncoder<BufWriter<File>>),
+}
+
+impl VideoEncoder {
+    fn new(format: ExportFormat, path: &Path, fps: u32) -> io::Result<Self> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let mut file = BufWriter::new(File::create(path)?);
+        match format {
+            ExportFormat::Gif => {
+                let mut encoder = GifEncoder::new(file);
+                encoder.set_repeat(Repeat::Infinite).map_err(io::Error::other)?;
+                Ok(VideoEncoder::Gif(encoder))
+            }
+            _ => {
+                writeln!(file, "YUV4MPEG2 W{} H{} F{}:1 Ip A1:1 C420jpeg", TEXTURE_SIZE.0, TEXTURE_SIZE.1, fps)?;
+                Ok(VideoEncoder::Y4m(file))
+            }
+        }
+    }
+
+    fn encode(&mut self, pixels: Vec<u8>, fps: u32) -> io::Result<()> {
+        match self {
+            VideoEncoder::Y4m(file) => {
+                file.write_all(b"FRAME\n")?;
+                file.write_all(&rgba_to_yuv420(&pixels, TEXTURE_SIZE.0 as usize, TEXTURE_SIZE.1 as usize))
+            }
+            VideoEncoder::Gif(encoder) => {
+                let image = RgbaImage::from_raw(TEXTURE_SIZE.0, TEXTURE_SIZE.1, pixels).unwrap();
+                let frame = Frame::from_parts(image, 0, 0, Delay::from_numer_denom_ms(1000, fps));
+                encoder.encode_frame(frame).map_err(io::Error::other)
+            }
+        }
+    }
+
+    fn finish(self) -> io::Result<()> {
+        match self {
+            VideoEncoder::Y4m(mut file) => file.flush(),
+            // the trailer is written when the encoder is dropped
+            VideoEncoder::Gif(_) => Ok(()),
+        }
+    }
+}
+
+/// Encodes on a thread of its own, GIF quantization is far too slow for the render world.
+fn spawn_video_writer(format: ExportFormat, path: &Path, fps: u32, progress: ExportProgress) -> io::Result<SyncSender<Vec<u8>>> {
+    let mut encoder = VideoEncoder::new(format, path, fps)?;
+    let (sender, receiver) = mpsc::sync_channel::<Vec<u8>>(VIDEO_QUEUE_LEN);
+    let path = path.to_path_buf();
+    std::thread::spawn(move || {
+        let result = receiver.iter().try_for_each(|pixels| encoder.encode(pixels, fps)).and_then(|()| encoder.finish());
+        if let Err(err) = result {
+            progress.0.lock().unwrap().error = Some(format!("failed to write {}: {}", path.display(), err));
+        }
+    });
+    Ok(sender)
+}
+
+/// Converts sRGB pixels to limited range BT.601 planes, chroma averaged over 2x2 blocks.
+fn rgba_to_yuv420(pixels: &[u8], width: usize, height: usize) -> Vec<u8> {
+    let rgb = |x: usize, y: usize| {
+        let idx = (y * width + x) * 4;
+        Vec3::new(pixels[idx] as f32, pixels[idx + 1] as f32, pixels[idx + 2] as f32) / 255.0
+    };
+
+    let mut planes = Vec::with_capacity(width * height * 3 / 2);
+    for y in 0..height {
+        for x in 0..width {
+            planes.push((16.0 + rgb(x, y).dot(Vec3::new(65.481, 128.553, 24.966))).round() as u8);
+        }
+    }
+    for weights in [Vec3::new(-37.797, -74.203, 112.0), Vec3::new(112.0, -93.786, -18.214)] {
+        for y in (0..height).step_by(2) {
+            for x in (0..width).step_by(2) {
+                let mean = (rgb(x, y) + rgb(x + 1, y) + rgb(x, y + 1) + rgb(x + 1, y + 1)) * 0.25;
+                planes.push((128.0 + mean.dot(weights)).round() as u8);
+            }
+        }
+    }
+    planes
+}
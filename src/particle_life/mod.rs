@@ -1,21 +1,36 @@
 use bevy::{prelude::*, render::{extract_resource::ExtractResourcePlugin, RenderApp, Render, render_graph::RenderGraph, RenderSet}};
 
-use self::{texture::{ParticleLifeImage, setup_texture}, buffers::{ParticlesBuffer, write_particles_buffer, write_vertex_buffer}, compute::{queue_bind_group, ParticleLifeNode, ParticleLifePipeline}, ui::UISettings, settings::{SettingsBuffer, extract_time, extract_ui_settings, prepare_settings_buffer}};
+use self::{texture::{ParticleLifeImage, setup_texture}, buffers::{ParticlesBuffer, GridBuffer, write_particles_buffer, write_grid_buffer, write_vertex_buffer}, compute::{queue_bind_group, queue_force_kernel, ParticleLifeNode, ParticleLifePipeline}, ui::UISettings, brush::{BrushCounts, EraseBuffer, apply_brush_edits, map_erase_buffer}, snapshot::{SnapshotStatus, save_snapshot}, readback::{ParticleReadback, ReadbackChannel, ReadbackBuffer, receive_particle_readback, prepare_readback, map_readback}, trajectory::{TrajectoryRecorder, TrajectoryPlayer, record_trajectory, advance_playback, write_playback_frame}, export::{ExportProgress, FrameExportBuffer, prepare_frame_export, write_exported_frame}, screenshot::{ScreenshotStatus, ScreenshotTarget, prepare_screenshot, write_screenshot}, stats::{ParticleStats, update_particle_stats}, clusters::{ParticleClusters, ClusterColorBuffer, update_clusters, prepare_cluster_colors}, settings::{SettingsBuffer, SimulationClock, extract_time, extract_ui_settings, prepare_settings_buffer}};
 
 pub mod compute;
 pub mod texture;
 pub mod buffers;
 pub mod settings;
+pub mod presets;
 pub mod ui;
+pub mod brush;
+pub mod spawn_image;
+pub mod snapshot;
+pub mod readback;
+pub mod trajectory;
+pub mod export;
+pub mod screenshot;
+pub mod neighbors;
+pub mod stats;
+pub mod clusters;
 
 
 pub const MAX_PARTICLE_TYPES: u32 = 16;
-pub const MAX_PARTICLES_PER_TYPE: u32 = 1024;
-pub const MAX_PARTICLES: u32 = MAX_PARTICLE_TYPES * MAX_PARTICLES_PER_TYPE;
+pub const MAX_PARTICLE_CAPACITY: u32 = 1 << 20;
+pub const INIT_PARTICLE_CAPACITY: u32 = 16384;
 
 pub const INIT_NUM_TYPES: u32 = 1;
 pub const INIT_NUM_PARTICLES_PER_TYPE: u32 = 128;
 pub const INIT_PARTICLE_RADIUS: f32 = 1.0;
+pub const INIT_SEED: u64 = 0;
+
+pub const MAX_GRID_DIM: u32 = 256;
+pub const MAX_GRID_CELLS: u32 = MAX_GRID_DIM * MAX_GRID_DIM;
 
 pub const TEXTURE_SIZE: (u32, u32) = (1280, 720);
 pub const WORKGROUP_SIZE: u32 = 64;
@@ -38,16 +53,48 @@ impl Plugin for ParticleLifeComputePlugin {
         app.add_state::<SimulationState>();
         app.add_systems(Startup, setup_texture);
         app.add_plugins(ExtractResourcePlugin::<ParticleLifeImage>::default());
+        let brush_counts = BrushCounts::default();
+        app.insert_resource(brush_counts.clone());
+        let snapshot_status = SnapshotStatus::default();
+        app.insert_resource(snapshot_status.clone());
+        let readback_channel = ReadbackChannel::default();
+        app.insert_resource(readback_channel.clone());
+        app.init_resource::<ParticleReadback>();
+        app.add_systems(PreUpdate, receive_particle_readback);
+        let export_progress = ExportProgress::default();
+        app.insert_resource(export_progress.clone());
+        let screenshot_status = ScreenshotStatus::default();
+        app.insert_resource(screenshot_status.clone());
+        app.init_resource::<TrajectoryRecorder>();
+        app.init_resource::<TrajectoryPlayer>();
+        app.init_resource::<ParticleStats>();
+        app.init_resource::<ParticleClusters>();
+        app.add_systems(Update, (record_trajectory, advance_playback, update_particle_stats, update_clusters));
 
         let render_app = app.sub_app_mut(RenderApp);
         render_app
             .init_resource::<SettingsBuffer>()
+            .init_resource::<SimulationClock>()
             .init_resource::<Time>()
             .init_resource::<UISettings>()
+            .insert_resource(brush_counts)
+            .insert_resource(snapshot_status)
+            .insert_resource(readback_channel)
+            .insert_resource(export_progress)
+            .insert_resource(screenshot_status)
+            .init_resource::<ScreenshotTarget>()
             .add_state::<SimulationState>()
             .add_systems(ExtractSchedule, (extract_time, extract_ui_settings))
-            .add_systems(Render, (prepare_settings_buffer, write_particles_buffer, write_vertex_buffer).in_set(RenderSet::Prepare))
-            .add_systems(Render, queue_bind_group.in_set(RenderSet::Queue));
+            .add_systems(Render, (prepare_settings_buffer, write_particles_buffer, write_grid_buffer, write_vertex_buffer).in_set(RenderSet::Prepare))
+            .add_systems(Render, save_snapshot.before(write_particles_buffer).in_set(RenderSet::Prepare))
+            .add_systems(Render, apply_brush_edits.after(write_particles_buffer).before(prepare_settings_buffer).in_set(RenderSet::Prepare))
+            .add_systems(Render, write_playback_frame.in_set(RenderSet::Prepare))
+            .add_systems(Render, prepare_readback.after(apply_brush_edits).in_set(RenderSet::Prepare))
+            .add_systems(Render, prepare_frame_export.in_set(RenderSet::Prepare))
+            .add_systems(Render, prepare_screenshot.in_set(RenderSet::Prepare))
+            .add_systems(Render, prepare_cluster_colors.in_set(RenderSet::Prepare))
+            .add_systems(Render, (map_readback, map_erase_buffer, write_exported_frame, write_screenshot).in_set(RenderSet::Cleanup))
+            .add_systems(Render, (queue_force_kernel, queue_bind_group).in_set(RenderSet::Queue));
         
         let mut render_graph = render_app.world.resource_mut::<RenderGraph>();
         render_graph.add_node("particle_life", ParticleLifeNode::default());
@@ -60,6 +107,11 @@ impl Plugin for ParticleLifeComputePlugin {
     fn finish(&self, app: &mut App) {
         let render_app = app.sub_app_mut(RenderApp);
         render_app.init_resource::<ParticlesBuffer>();
+        render_app.init_resource::<GridBuffer>();
+        render_app.init_resource::<ReadbackBuffer>();
+        render_app.init_resource::<EraseBuffer>();
+        render_app.init_resource::<FrameExportBuffer>();
+        render_app.init_resource::<ClusterColorBuffer>();
         render_app.init_resource::<ParticleLifePipeline>();
     }
 }
\ No newline at end of file
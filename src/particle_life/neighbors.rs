@@ -0,0 +1,102 @@
+use bevy::prelude::*;
+
+use super::{buffers::Particle, ui::BoundaryMode, TEXTURE_SIZE};
+
+
+/// Limits the memory of the grid when the radius is tiny.
+const MAX_CELLS_PER_AXIS: u32 = 1024;
+
+/// Uniform grid over the particles of a readback, for finding the particles within `radius`
+/// of each other on the CPU. Cells are at least `radius` wide, so only neighboring cells
+/// need to be searched, like in the compute shader.
+pub struct NeighborGrid {
+    radius: f32,
+    extent: Vec2,
+    /// Axes along which the world wraps around, distances follow the minimum image convention.
+    wrap: BVec2,
+    dims: UVec2,
+    /// Start of each cell in `sorted`, with one extra entry for the end of the last cell.
+    cell_starts: Vec<u32>,
+    sorted: Vec<u32>,
+}
+
+impl NeighborGrid {
+    pub fn new(particles: &[Particle], radius: f32, boundary: BoundaryMode) -> Self {
+        let extent = world_extent();
+        let dims = (extent / radius.max(f32::EPSILON)).floor().as_uvec2().clamp(UVec2::ONE, UVec2::splat(MAX_CELLS_PER_AXIS));
+        let mut grid = Self {
+            radius,
+            extent,
+            wrap: boundary.wrap_axes(),
+            dims,
+            cell_starts: vec![0; (dims.x * dims.y) as usize + 1],
+            sorted: vec![0; particles.len()],
+        };
+
+        // counting sort of the particles by cell
+        let cells: Vec<u32> = particles.iter().map(|p| grid.cell_index(grid.cell_coord(Vec2::from(p.pos)))).collect();
+        for &cell in &cells {
+            grid.cell_starts[cell as usize + 1] += 1;
+        }
+        for cell in 1..grid.cell_starts.len() {
+            grid.cell_starts[cell] += grid.cell_starts[cell - 1];
+        }
+        let mut next = grid.cell_starts.clone();
+        for (idx, &cell) in cells.iter().enumerate() {
+            grid.sorted[next[cell as usize] as usize] = idx as u32;
+            next[cell as usize] += 1;
+        }
+        grid
+    }
+
+    fn cell_coord(&self, pos: Vec2) -> UVec2 {
+        let coord = (pos / self.extent * self.dims.as_vec2()).floor().as_ivec2();
+        coord.clamp(IVec2::ZERO, self.dims.as_ivec2() - 1).as_uvec2()
+    }
+
+    fn cell_index(&self, coord: UVec2) -> u32 {
+        coord.y * self.dims.x + coord.x
+    }
+
+    /// Offset from `from` to `to`, across the edges of the world where it wraps.
+    pub fn delta(&self, from: Vec2, to: Vec2) -> Vec2 {
+        let delta = to - from;
+        let wrapped = delta - self.extent * (delta / self.extent).round();
+        Vec2::select(self.wrap, wrapped, delta)
+    }
+
+    /// Calls `f` with the index of every other particle within the radius of particle `idx`.
+    pub fn for_each_neighbor(&self, particles: &[Particle], idx: usize, mut f: impl FnMut(usize)) {
+        let pos = Vec2::from(particles[idx].pos);
+        let coord = self.cell_coord(pos);
+        let axis_cells = |c: u32, dim: u32, wrap: bool| -> Vec<u32> {
+            // with fewer than three cells the neighboring ones would repeat
+            if dim <= 2 {
+                return (0..dim).collect();
+            }
+            [c as i32 - 1, c as i32, c as i32 + 1].into_iter()
+                .filter_map(|n| match wrap {
+                    true => Some(n.rem_euclid(dim as i32) as u32),
+                    false => (0..dim as i32).contains(&n).then_some(n as u32),
+                })
+                .collect()
+        };
+
+        for y in axis_cells(coord.y, self.dims.y, self.wrap.y) {
+            for x in axis_cells(coord.x, self.dims.x, self.wrap.x) {
+                let cell = self.cell_index(UVec2::new(x, y)) as usize;
+                for &other in &self.sorted[self.cell_starts[cell] as usize..self.cell_starts[cell + 1] as usize] {
+                    let other = other as usize;
+                    if other != idx && self.delta(pos, Vec2::from(particles[other].pos)).length_squared() <= self.radius * self.radius {
+                        f(other);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Size of the simulated world, `[0, 1 / aspect ratio) x [0, 1)`.
+pub fn world_extent() -> Vec2 {
+    Vec2::new(TEXTURE_SIZE.0 as f32 / TEXTURE_SIZE.1 as f32, 1.0)
+}
@@ -0,0 +1,216 @@
+use std::{fmt, fs, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+use super::{ui::{UISettings, ForceKernel}, buffers::create_particle_colors, MAX_PARTICLE_TYPES, MAX_PARTICLE_CAPACITY, TEXTURE_SIZE};
+
+
+pub const DEFAULT_PRESET_PATH: &str = "presets/cells-1.json";
+
+/// Preset radii are stored in pixels of the texture height, while the simulation works in
+/// units where the texture height is 1.
+const PRESET_RADIUS_SCALE: f32 = TEXTURE_SIZE.1 as f32;
+
+
+/// On-disk layout of the files in `presets/`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Preset {
+    pub n_types: u32,
+    /// Count shared by all types, kept for presets without `particle_counts`.
+    pub particle_per_type: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub particle_counts: Option<Vec<u32>>,
+    pub attraction_matrix: Vec<Vec<f32>>,
+    pub min_r_matrix: Vec<Vec<f32>>,
+    pub max_r_matrix: Vec<Vec<f32>>,
+    pub friction: f32,
+    #[serde(default)]
+    pub force_kernel: ForceKernel,
+    /// Seed of the initial particle positions.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u64>,
+}
+
+#[derive(Debug)]
+pub enum PresetError {
+    Io(std::io::Error),
+    Write(std::io::Error),
+    Parse(serde_json::Error),
+    Invalid(String),
+}
+
+impl fmt::Display for PresetError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PresetError::Io(err) => write!(f, "could not read preset: {}", err),
+            PresetError::Write(err) => write!(f, "could not write preset: {}", err),
+            PresetError::Parse(err) => write!(f, "malformed preset: {}", err),
+            PresetError::Invalid(msg) => write!(f, "invalid preset: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for PresetError {}
+
+impl From<std::io::Error> for PresetError {
+    fn from(err: std::io::Error) -> Self {
+        PresetError::Io(err)
+    }
+}
+
+impl From<serde_json::Error> for PresetError {
+    fn from(err: serde_json::Error) -> Self {
+        PresetError::Parse(err)
+    }
+}
+
+impl Preset {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, PresetError> {
+        let contents = fs::read_to_string(path)?;
+        let preset: Preset = serde_json::from_str(&contents)?;
+        preset.validate()?;
+        Ok(preset)
+    }
+
+    pub fn from_settings(settings: &UISettings) -> Self {
+        let n = settings.num_particle_types as usize;
+
+        let particle_per_type = settings.particles_per_type.iter().copied().max().unwrap_or(0);
+        let uniform_counts = settings.particles_per_type.iter().all(|&count| count == particle_per_type);
+
+        Self {
+            n_types: settings.num_particle_types,
+            particle_per_type,
+            particle_counts: if uniform_counts { None } else { Some(settings.particles_per_type.clone()) },
+            attraction_matrix: to_matrix(n, |idx| settings.attraction_table[idx]),
+            min_r_matrix: to_matrix(n, |idx| settings.min_r_table[idx] * settings.max_r_table[idx] * PRESET_RADIUS_SCALE),
+            max_r_matrix: to_matrix(n, |idx| settings.max_r_table[idx] * PRESET_RADIUS_SCALE),
+            friction: settings.friction_half_time,
+            force_kernel: settings.force_kernel,
+            seed: Some(settings.active_seed),
+        }
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), PresetError> {
+        let mut contents = Vec::new();
+        let formatter = serde_json::ser::PrettyFormatter::with_indent(b"    ");
+        let mut serializer = serde_json::Serializer::with_formatter(&mut contents, formatter);
+        self.serialize(&mut serializer)?;
+        fs::write(path, contents).map_err(PresetError::Write)?;
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<(), PresetError> {
+        if self.n_types == 0 || self.n_types > MAX_PARTICLE_TYPES {
+            return Err(PresetError::Invalid(format!("n_types must be between 1 and {}, got {}", MAX_PARTICLE_TYPES, self.n_types)));
+        }
+        if let Some(counts) = &self.particle_counts {
+            if counts.len() != self.n_types as usize {
+                return Err(PresetError::Invalid(format!("particle_counts must have {} entries, got {}", self.n_types, counts.len())));
+            }
+        }
+        let n_particles: u64 = self.counts().iter().map(|&count| count as u64).sum();
+        if n_particles > MAX_PARTICLE_CAPACITY as u64 {
+            return Err(PresetError::Invalid(format!("at most {} particles are supported, got {}", MAX_PARTICLE_CAPACITY, n_particles)));
+        }
+        if !self.friction.is_finite() || self.friction <= 0.0 {
+            return Err(PresetError::Invalid(format!("friction must be positive, got {}", self.friction)));
+        }
+        for (name, matrix) in [
+            ("attraction_matrix", &self.attraction_matrix),
+            ("min_r_matrix", &self.min_r_matrix),
+            ("max_r_matrix", &self.max_r_matrix),
+        ] {
+            if matrix.len() != self.n_types as usize || matrix.iter().any(|row| row.len() != self.n_types as usize) {
+                return Err(PresetError::Invalid(format!("{} must be {}x{}", name, self.n_types, self.n_types)));
+            }
+            if matrix.iter().flatten().any(|v| !v.is_finite()) {
+                return Err(PresetError::Invalid(format!("{} contains a non-finite value", name)));
+            }
+        }
+        Ok(())
+    }
+
+    fn counts(&self) -> Vec<u32> {
+        match &self.particle_counts {
+            Some(counts) => counts.clone(),
+            None => vec![self.particle_per_type; self.n_types as usize],
+        }
+    }
+
+    pub fn apply(&self, settings: &mut UISettings) {
+        settings.num_particle_types = self.n_types;
+        settings.particles_per_type = self.counts();
+        settings.friction_half_time = self.friction;
+        settings.force_kernel = self.force_kernel;
+        if let Some(seed) = self.seed {
+            settings.seed = seed;
+        }
+        let n_particles = settings.num_particles();
+        settings.reserve_particles(n_particles);
+
+        // the global sliders show the average over all type pairs
+        let n_pairs = (self.n_types * self.n_types) as usize;
+        let mean_max_r = self.max_r_matrix.iter().flatten().map(|r| r.max(0.0)).sum::<f32>() / n_pairs as f32;
+        let mean_min_r = self.min_r_matrix.iter().flatten().map(|r| r.max(0.0)).sum::<f32>() / n_pairs as f32;
+        settings.max_r = (mean_max_r / PRESET_RADIUS_SCALE).clamp(0.0, 1.0);
+        settings.min_r = if mean_max_r > 0.0 { (mean_min_r / mean_max_r).clamp(0.0, 1.0) } else { 0.0 };
+
+        settings.attraction_table = vec![0.0; n_pairs];
+        settings.min_r_table = vec![0.0; n_pairs];
+        settings.max_r_table = vec![0.0; n_pairs];
+        for i in 0..self.n_types as usize {
+            for j in 0..self.n_types as usize {
+                let idx = i * self.n_types as usize + j;
+                // a negative radius disables the interaction between the pair
+                let max_r = self.max_r_matrix[i][j].max(0.0);
+                let min_r = self.min_r_matrix[i][j].max(0.0);
+                settings.attraction_table[idx] = self.attraction_matrix[i][j].clamp(-1.0, 1.0);
+                settings.max_r_table[idx] = (max_r / PRESET_RADIUS_SCALE).clamp(0.0, 1.0);
+                settings.min_r_table[idx] = if max_r > 0.0 { (min_r / max_r).clamp(0.0, 1.0) } else { 0.0 };
+            }
+        }
+
+        settings.ptype_colors = create_particle_colors(self.n_types);
+        settings.particle_count_changed = true;
+    }
+}
+
+
+fn to_matrix(n: usize, f: impl Fn(usize) -> f32) -> Vec<Vec<f32>> {
+    (0..n).map(|i| (0..n).map(|j| f(i * n + j)).collect()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let mut settings = UISettings::default();
+        settings.particles_per_type[0] += 3;
+        settings.force_kernel = ForceKernel::ALL[2];
+        settings.active_seed = 42;
+        let saved = Preset::from_settings(&settings);
+        let path = std::env::temp_dir().join(format!("particle_life_preset_{}.json", std::process::id()));
+        saved.save(&path).unwrap();
+        let loaded = Preset::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded.n_types, saved.n_types);
+        assert_eq!(loaded.particle_per_type, saved.particle_per_type);
+        assert_eq!(loaded.particle_counts, saved.particle_counts);
+        assert_eq!(loaded.attraction_matrix, saved.attraction_matrix);
+        assert_eq!(loaded.min_r_matrix, saved.min_r_matrix);
+        assert_eq!(loaded.max_r_matrix, saved.max_r_matrix);
+        assert_eq!(loaded.friction, saved.friction);
+        assert_eq!(loaded.force_kernel, saved.force_kernel);
+        assert_eq!(loaded.seed, Some(42));
+
+        let mut applied = UISettings::default();
+        loaded.apply(&mut applied);
+        assert_eq!(applied.particles_per_type, settings.particles_per_type);
+        assert_eq!(applied.attraction_table, settings.attraction_table);
+        assert_eq!(applied.seed, 42);
+    }
+}
@@ -0,0 +1,131 @@
+use std::sync::{Arc, Mutex};
+
+use bevy::{prelude::*, render::{render_resource::{Buffer, BufferDescriptor, BufferUsages, MapMode}, renderer::RenderDevice}};
+use wgpu::Maintain;
+
+use super::{buffers::{Particle, ParticlesBuffer, particles_size}, ui::UISettings, INIT_PARTICLE_CAPACITY};
+
+
+/// Particles as of the latest readback, every `UISettings::readback_interval` frames.
+/// Lags the simulation by a frame or two, as the copy is mapped asynchronously.
+#[derive(Resource, Default)]
+pub struct ParticleReadback {
+    pub particles: Vec<Particle>,
+}
+
+/// Hands finished readbacks from the render world to the main world.
+#[derive(Resource, Clone, Default)]
+pub struct ReadbackChannel(Arc<Mutex<Option<Vec<Particle>>>>);
+
+pub fn receive_particle_readback(
+    channel: Res<ReadbackChannel>,
+    mut readback: ResMut<ParticleReadback>,
+) {
+    if let Some(particles) = channel.0.lock().unwrap().take() {
+        readback.particles = particles;
+    }
+}
+
+enum ReadbackState {
+    Idle,
+    /// The node copies this many particles into the readback buffer this frame.
+    Copying(u32),
+    /// Waiting for the copy of this many particles to be mapped, the callback stores
+    /// whether mapping succeeded.
+    Mapping(u32, Arc<Mutex<Option<bool>>>),
+}
+
+/// A buffer of its own, the staging buffer is drawn from every frame and cannot stay mapped.
+#[derive(Resource)]
+pub struct ReadbackBuffer {
+    buffer: Buffer,
+    capacity: u32,
+    state: ReadbackState,
+    frames_since_readback: u32,
+}
+
+impl FromWorld for ReadbackBuffer {
+    fn from_world(world: &mut World) -> Self {
+        let device = world.resource::<RenderDevice>();
+        Self {
+            buffer: create_readback_buffer(device, INIT_PARTICLE_CAPACITY),
+            capacity: INIT_PARTICLE_CAPACITY,
+            state: ReadbackState::Idle,
+            frames_since_readback: 0,
+        }
+    }
+}
+
+impl ReadbackBuffer {
+    /// Buffer the node copies the latest particles into this frame and how many, if any.
+    pub fn copy_request(&self) -> Option<(&Buffer, u32)> {
+        match self.state {
+            ReadbackState::Copying(n_particles) => Some((&self.buffer, n_particles)),
+            _ => None,
+        }
+    }
+}
+
+fn create_readback_buffer(device: &RenderDevice, capacity: u32) -> Buffer {
+    device.create_buffer(&BufferDescriptor {
+        label: None,
+        size: particles_size(capacity),
+        usage: BufferUsages::MAP_READ | BufferUsages::COPY_DST,
+        mapped_at_creation: false,
+    })
+}
+
+/// Publishes a finished readback and schedules the next copy once the interval is up.
+pub fn prepare_readback(
+    mut readback_buf: ResMut<ReadbackBuffer>,
+    particles_buf: Res<ParticlesBuffer>,
+    settings: Res<UISettings>,
+    channel: Res<ReadbackChannel>,
+    render_device: Res<RenderDevice>,
+) {
+    readback_buf.frames_since_readback += 1;
+
+    if let ReadbackState::Mapping(n_particles, map_result) = &readback_buf.state {
+        render_device.wgpu_device().poll(Maintain::Poll);
+        let n_particles = *n_particles;
+        let Some(mapped) = *map_result.lock().unwrap() else { return; };
+        if mapped {
+            let slice = readback_buf.buffer.slice(..particles_size(n_particles));
+            let particles = bytemuck::cast_slice(&slice.get_mapped_range()).to_vec();
+            readback_buf.buffer.unmap();
+            *channel.0.lock().unwrap() = Some(particles);
+        }
+        readback_buf.state = ReadbackState::Idle;
+    }
+
+    if !matches!(readback_buf.state, ReadbackState::Idle)
+        || settings.readback_interval == 0
+        || readback_buf.frames_since_readback < settings.readback_interval {
+        return;
+    }
+    let n_particles = settings.num_particles().min(particles_buf.capacity);
+    if readback_buf.capacity < n_particles {
+        readback_buf.buffer = create_readback_buffer(&render_device, particles_buf.capacity);
+        readback_buf.capacity = particles_buf.capacity;
+    }
+    readback_buf.state = if n_particles > 0 { ReadbackState::Copying(n_particles) } else { ReadbackState::Idle };
+    if n_particles == 0 {
+        *channel.0.lock().unwrap() = Some(Vec::new());
+    }
+    readback_buf.frames_since_readback = 0;
+}
+
+/// Maps the copy made by the node, after the frame's commands have been submitted.
+pub fn map_readback(
+    mut readback_buf: ResMut<ReadbackBuffer>,
+    render_device: Res<RenderDevice>,
+) {
+    let ReadbackState::Copying(n_particles) = readback_buf.state else { return; };
+    let map_result = Arc::new(Mutex::new(None));
+    let map_result_callback = map_result.clone();
+    let slice = readback_buf.buffer.slice(..particles_size(n_particles));
+    render_device.map_buffer(&slice, MapMode::Read, move |result| {
+        *map_result_callback.lock().unwrap() = Some(result.is_ok());
+    });
+    readback_buf.state = ReadbackState::Mapping(n_particles, map_result);
+}
@@ -0,0 +1,116 @@
+use std::{path::PathBuf, sync::{Arc, Mutex}, time::{SystemTime, UNIX_EPOCH}};
+
+use bevy::{prelude::*, tasks::IoTaskPool, render::{render_resource::{Buffer, BufferDescriptor, BufferUsages, MapMode, ImageDataLayout, Texture, TextureView, TextureDescriptor, TextureDimension, TextureFormat, TextureUsages, TextureViewDescriptor, Extent3d}, renderer::RenderDevice, texture::BevyDefault}};
+use wgpu::Maintain;
+
+use super::{ui::UISettings, export::padded_bytes_per_row};
+
+
+pub const DEFAULT_SCREENSHOT_DIR: &str = "screenshots";
+
+/// Result of the last screenshot, written from the render world.
+#[derive(Resource, Clone, Default)]
+pub struct ScreenshotStatus(pub Arc<Mutex<Option<Result<String, String>>>>);
+
+/// Offscreen target the node draws the particles into, and the buffer it is copied to.
+pub struct PendingScreenshot {
+    pub texture: Texture,
+    pub view: TextureView,
+    pub buffer: Buffer,
+    pub size: Extent3d,
+    path: PathBuf,
+}
+
+impl PendingScreenshot {
+    pub fn data_layout(&self) -> ImageDataLayout {
+        ImageDataLayout {
+            offset: 0,
+            bytes_per_row: Some(padded_bytes_per_row(self.size.width)),
+            rows_per_image: None,
+        }
+    }
+}
+
+/// Created only for the frame a screenshot is taken, large resolutions take a lot of memory.
+#[derive(Resource, Default)]
+pub struct ScreenshotTarget(pub Option<PendingScreenshot>);
+
+pub fn prepare_screenshot(
+    mut target: ResMut<ScreenshotTarget>,
+    settings: Res<UISettings>,
+    status: Res<ScreenshotStatus>,
+    render_device: Res<RenderDevice>,
+) {
+    if !settings.screenshot_requested { return; }
+
+    let (width, height) = settings.screenshot_size();
+    let max_dim = render_device.limits().max_texture_dimension_2d;
+    if width > max_dim || height > max_dim {
+        *status.0.lock().unwrap() = Some(Err(format!("{}x{} is larger than the {} pixels this GPU supports", width, height, max_dim)));
+        return;
+    }
+    let size = Extent3d {
+        width,
+        height,
+        depth_or_array_layers: 1,
+    };
+    // same format as the output image, so that the particle render pipeline can draw into it
+    let texture = render_device.create_texture(&TextureDescriptor {
+        label: None,
+        size,
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: TextureDimension::D2,
+        format: TextureFormat::bevy_default(),
+        usage: TextureUsages::RENDER_ATTACHMENT | TextureUsages::COPY_SRC,
+        view_formats: &[],
+    });
+    let view = texture.create_view(&TextureViewDescriptor::default());
+    let buffer = render_device.create_buffer(&BufferDescriptor {
+        label: None,
+        size: padded_bytes_per_row(width) as u64 * height as u64,
+        usage: BufferUsages::MAP_READ | BufferUsages::COPY_DST,
+        mapped_at_creation: false,
+    });
+    let path = PathBuf::from(&settings.screenshot_dir).join(format!("screenshot_{}.png", timestamp()));
+    target.0 = Some(PendingScreenshot { texture, view, buffer, size, path });
+}
+
+/// Reads back the screenshot drawn by the node and saves it as a PNG.
+pub fn write_screenshot(
+    mut target: ResMut<ScreenshotTarget>,
+    status: Res<ScreenshotStatus>,
+    render_device: Res<RenderDevice>,
+) {
+    let Some(screenshot) = target.0.take() else { return; };
+
+    let slice = screenshot.buffer.slice(..);
+    render_device.map_buffer(&slice, MapMode::Read, |_| {});
+    render_device.wgpu_device().poll(Maintain::Wait);
+    let padded_row = padded_bytes_per_row(screenshot.size.width) as usize;
+    let row = screenshot.size.width as usize * 4;
+    let mut pixels = Vec::with_capacity(row * screenshot.size.height as usize);
+    for padded in slice.get_mapped_range().chunks_exact(padded_row) {
+        pixels.extend_from_slice(&padded[..row]);
+    }
+    screenshot.buffer.unmap();
+    pixels.chunks_exact_mut(4).for_each(|pixel| pixel[3] = 255);
+
+    let status = status.clone();
+    let PendingScreenshot { path, size, .. } = screenshot;
+    IoTaskPool::get().spawn(async move {
+        if let Some(dir) = path.parent() {
+            let _ = std::fs::create_dir_all(dir);
+        }
+        let result = match image::save_buffer(&path, &pixels, size.width, size.height, image::ColorType::Rgba8) {
+            Ok(()) => Ok(format!("Saved {}", path.display())),
+            Err(err) => Err(format!("failed to write {}: {}", path.display(), err)),
+        };
+        *status.0.lock().unwrap() = Some(result);
+    }).detach();
+}
+
+/// Milliseconds since the Unix epoch, so that screenshots sort by when they were taken.
+fn timestamp() -> u128 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis()
+}
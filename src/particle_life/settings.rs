@@ -1,6 +1,6 @@
 use bevy::{prelude::*, render::{render_resource::{UniformBuffer, ShaderType, StorageBuffer}, Extract, renderer::{RenderDevice, RenderQueue}, extract_resource::ExtractResource}};
 
-use super::{ui::UISettings, TEXTURE_SIZE, MAX_PARTICLE_TYPES};
+use super::{ui::{UISettings, BrushTool}, brush::BrushCounts, TEXTURE_SIZE, MAX_GRID_DIM};
 
 
 #[derive(Default, Clone, Resource, ExtractResource, Reflect, ShaderType)]
@@ -13,11 +13,18 @@ pub struct SettingsUniform {
     pub n_types: u32,
     pub n_particles: u32,
 
-    pub min_r: f32,
     pub max_r: f32,
     pub friction: f32,
     pub speed: f32,
-    pub wrap: i32,
+    pub boundary: u32,
+
+    pub grid_width: u32,
+    pub grid_height: u32,
+
+    pub brush_pos: Vec2,
+    pub brush_radius: f32,
+    /// Positive attracts, negative repels, zero while the force brush is not in use.
+    pub brush_force: f32,
     
     // #[cfg(all(feature = "webgl", target_arch = "wasm32"))]
     // _padding: f32,
@@ -28,7 +35,9 @@ pub struct SettingsUniform {
 pub struct SettingsBuffer {
     pub settings: UniformBuffer<SettingsUniform>,
     pub aspect_ratio: UniformBuffer<f32>,
-    pub attraction_tables: StorageBuffer<[f32; (MAX_PARTICLE_TYPES * MAX_PARTICLE_TYPES) as usize]>,
+    pub attraction_tables: StorageBuffer<Vec<f32>>,
+    pub min_r_tables: StorageBuffer<Vec<f32>>,
+    pub max_r_tables: StorageBuffer<Vec<f32>>,
 }
 
 impl Default for SettingsBuffer {
@@ -36,48 +45,103 @@ impl Default for SettingsBuffer {
         Self {
             settings: UniformBuffer::default(),
             aspect_ratio: UniformBuffer::default(),
-            attraction_tables: StorageBuffer::from([0.0; (MAX_PARTICLE_TYPES * MAX_PARTICLE_TYPES) as usize]),
+            attraction_tables: StorageBuffer::default(),
+            min_r_tables: StorageBuffer::default(),
+            max_r_tables: StorageBuffer::default(),
         }
     }
 }
 
+/// Accumulates real time into whole simulation steps of `UISettings::time_step`.
+#[derive(Resource, Default)]
+pub struct SimulationClock {
+    pub accumulator: f32,
+    /// Number of steps to simulate this frame.
+    pub steps: u32,
+    /// Holds the simulation while the particles are edited from a copy, which would otherwise
+    /// be outdated by the time it is written back.
+    pub paused: bool,
+}
+
 pub fn extract_time(mut commands: Commands, time: Extract<Res<Time>>) {
     commands.insert_resource(time.clone());
 }
 
-pub fn extract_ui_settings(mut commands: Commands, settings: Extract<Res<UISettings>>) {
-    commands.insert_resource(settings.clone());
+pub fn extract_ui_settings(mut commands: Commands, settings: Extract<Res<UISettings>>, brush_counts: Res<BrushCounts>) {
+    let mut settings = settings.clone();
+    brush_counts.override_counts(&mut settings);
+    commands.insert_resource(settings);
 }
 
 pub fn prepare_settings_buffer(
     device: Res<RenderDevice>,
     queue: Res<RenderQueue>,
     mut settings_buffer: ResMut<SettingsBuffer>,
+    mut clock: ResMut<SimulationClock>,
     settings: Res<UISettings>,
     time: Res<Time>,
 ) {
+    if clock.paused {
+        clock.accumulator = 0.0;
+        clock.steps = 0;
+    } else if settings.running && settings.frame_export_active {
+        // exported frames each advance the same simulated time, however long they take to render
+        clock.accumulator = 0.0;
+        clock.steps = settings.export_steps_per_frame;
+    } else if settings.running {
+        clock.accumulator += time.delta_seconds();
+        let steps = (clock.accumulator / settings.time_step) as u32;
+        clock.steps = steps.min(settings.max_substeps);
+        // drop the time that could not be caught up on, instead of letting it pile up
+        clock.accumulator = if steps > clock.steps { 0.0 } else { clock.accumulator - steps as f32 * settings.time_step };
+    } else {
+        clock.accumulator = 0.0;
+        clock.steps = 0;
+    }
+
     let aspect_ratio_val = TEXTURE_SIZE.1 as f32 / TEXTURE_SIZE.0 as f32;
     let aspect_ratio = settings_buffer.aspect_ratio.get_mut();
     *aspect_ratio = aspect_ratio_val;
 
     let settings_uniform = settings_buffer.settings.get_mut();
-    settings_uniform.delta_time = time.delta_seconds();
+    settings_uniform.delta_time = settings.time_step;
     settings_uniform.time = time.elapsed_seconds();
     settings_uniform.inv_aspect_ratio = 1.0 / aspect_ratio_val;
     
     settings_uniform.n_types = settings.num_particle_types;
-    settings_uniform.n_particles = settings.num_particle_types * settings.num_particles_per_type;
+    settings_uniform.n_particles = settings.num_particles();
 
-    settings_uniform.min_r = settings.min_r;
-    settings_uniform.max_r = settings.max_r;
+    settings_uniform.max_r = settings.active_max_r();
     settings_uniform.friction = 0.5f32.powf(settings_uniform.delta_time / settings.friction_half_time);
     settings_uniform.speed = settings.speed;
-    settings_uniform.wrap = if settings.wrap { 1 } else { 0 };
+    settings_uniform.boundary = settings.boundary as u32;
+
+    // grid cells must be at least as large as the interaction radius so that only
+    // neighboring cells need to be searched
+    let max_r = settings_uniform.max_r;
+    let grid_dim = |extent: f32| if max_r > 0.0 { ((extent / max_r) as u32).clamp(1, MAX_GRID_DIM) } else { MAX_GRID_DIM };
+    settings_uniform.grid_width = grid_dim(settings_uniform.inv_aspect_ratio);
+    settings_uniform.grid_height = grid_dim(1.0);
+
+    settings_uniform.brush_radius = settings.brush_radius;
+    (settings_uniform.brush_pos, settings_uniform.brush_force) = match settings.brush_stroke {
+        Some(stroke) if settings.brush_tool == BrushTool::Force => {
+            let sign = if stroke.secondary { -1.0 } else { 1.0 };
+            (Vec2::from(stroke.pos), sign * settings.brush_strength)
+        }
+        _ => (Vec2::ZERO, 0.0),
+    };
 
     let attractions = settings_buffer.attraction_tables.get_mut();
-    *attractions = settings.attraction_table;
+    attractions.clone_from(&settings.attraction_table);
+    let min_rs = settings_buffer.min_r_tables.get_mut();
+    min_rs.clone_from(&settings.min_r_table);
+    let max_rs = settings_buffer.max_r_tables.get_mut();
+    max_rs.clone_from(&settings.max_r_table);
 
     settings_buffer.attraction_tables.write_buffer(&device, &queue);
+    settings_buffer.min_r_tables.write_buffer(&device, &queue);
+    settings_buffer.max_r_tables.write_buffer(&device, &queue);
     settings_buffer.settings.write_buffer(&device, &queue);
     settings_buffer.aspect_ratio.write_buffer(&device, &queue);
 }
\ No newline at end of file
@@ -0,0 +1,389 @@
+use std::{fmt, fs, path::Path, sync::{Arc, Mutex}};
+
+use bevy::{prelude::*, render::renderer::RenderDevice};
+
+use super::{buffers::{Particle, ParticlesBuffer, aligned_capacity, create_particle_colors, read_staging_particles}, ui::{UISettings, BoundaryMode, ForceKernel}, MAX_PARTICLE_TYPES, MAX_PARTICLE_CAPACITY};
+
+
+pub const DEFAULT_SNAPSHOT_PATH: &str = "snapshots/snapshot.bin";
+
+const MAGIC: &[u8; 8] = b"PLSNAP\0\0";
+const VERSION: u32 = 1;
+
+
+/// Every particle plus the settings needed to continue the simulation from it.
+///
+/// Stored little endian as the magic and version, the settings, the per type tables and
+/// then `pos`, `vel` and `type_idx` of every particle. Colors follow from the types.
+pub struct Snapshot {
+    pub n_types: u32,
+    pub particles_per_type: Vec<u32>,
+    pub particle_capacity: u32,
+    pub attraction_table: Vec<f32>,
+    pub min_r_table: Vec<f32>,
+    pub max_r_table: Vec<f32>,
+    pub min_r: f32,
+    pub max_r: f32,
+    pub friction_half_time: f32,
+    pub speed: f32,
+    pub boundary: BoundaryMode,
+    pub force_kernel: ForceKernel,
+    pub time_step: f32,
+    pub max_substeps: u32,
+    pub seed: u64,
+    pub particles: Vec<Particle>,
+}
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    Io(std::io::Error),
+    Invalid(String),
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SnapshotError::Io(err) => write!(f, "snapshot file error: {}", err),
+            SnapshotError::Invalid(msg) => write!(f, "invalid snapshot: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+impl From<std::io::Error> for SnapshotError {
+    fn from(err: std::io::Error) -> Self {
+        SnapshotError::Io(err)
+    }
+}
+
+impl Snapshot {
+    pub fn from_settings(settings: &UISettings, particles: Vec<Particle>) -> Self {
+        Self {
+            n_types: settings.num_particle_types,
+            particles_per_type: settings.particles_per_type.clone(),
+            particle_capacity: settings.particle_capacity,
+            attraction_table: settings.attraction_table.clone(),
+            min_r_table: settings.min_r_table.clone(),
+            max_r_table: settings.max_r_table.clone(),
+            min_r: settings.min_r,
+            max_r: settings.max_r,
+            friction_half_time: settings.friction_half_time,
+            speed: settings.speed,
+            boundary: settings.boundary,
+            force_kernel: settings.force_kernel,
+            time_step: settings.time_step,
+            max_substeps: settings.max_substeps,
+            seed: settings.active_seed,
+            particles,
+        }
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), SnapshotError> {
+        let mut writer = Writer(Vec::with_capacity(self.particles.len() * 20));
+        writer.0.extend_from_slice(MAGIC);
+        writer.u32(VERSION);
+
+        writer.u32(self.n_types);
+        writer.u32(self.particles.len() as u32);
+        writer.u32(self.particle_capacity);
+        writer.f32(self.min_r);
+        writer.f32(self.max_r);
+        writer.f32(self.friction_half_time);
+        writer.f32(self.speed);
+        writer.u32(self.boundary as u32);
+        writer.u32(ForceKernel::ALL.iter().position(|&kernel| kernel == self.force_kernel).unwrap_or(0) as u32);
+        writer.f32(self.time_step);
+        writer.u32(self.max_substeps);
+        writer.u64(self.seed);
+
+        self.particles_per_type.iter().for_each(|&count| writer.u32(count));
+        for table in [&self.attraction_table, &self.min_r_table, &self.max_r_table] {
+            table.iter().for_each(|&v| writer.f32(v));
+        }
+        for p in &self.particles {
+            p.pos.iter().chain(&p.vel).for_each(|&v| writer.f32(v));
+            writer.u32(p.type_idx);
+        }
+
+        if let Some(dir) = path.as_ref().parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(path, writer.0)?;
+        Ok(())
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, SnapshotError> {
+        let contents = fs::read(path)?;
+        let mut reader = Reader(&contents);
+        if reader.bytes(MAGIC.len())? != MAGIC {
+            return Err(SnapshotError::Invalid(String::from("not a snapshot file")));
+        }
+        let version = reader.u32()?;
+        if version != VERSION {
+            return Err(SnapshotError::Invalid(format!("unsupported version {}", version)));
+        }
+
+        let n_types = reader.u32()?;
+        if n_types == 0 || n_types > MAX_PARTICLE_TYPES {
+            return Err(SnapshotError::Invalid(format!("n_types must be between 1 and {}, got {}", MAX_PARTICLE_TYPES, n_types)));
+        }
+        let n_particles = reader.u32()?;
+        if n_particles > MAX_PARTICLE_CAPACITY {
+            return Err(SnapshotError::Invalid(format!("at most {} particles are supported, got {}", MAX_PARTICLE_CAPACITY, n_particles)));
+        }
+        let particle_capacity = reader.u32()?;
+        let min_r = reader.f32()?;
+        let max_r = reader.f32()?;
+        let friction_half_time = reader.f32()?;
+        let speed = reader.f32()?;
+        let boundary = reader.u32()?;
+        let boundary = *BoundaryMode::ALL.get(boundary as usize)
+            .ok_or_else(|| SnapshotError::Invalid(format!("unknown boundary mode {}", boundary)))?;
+        let force_kernel = reader.u32()?;
+        let force_kernel = *ForceKernel::ALL.get(force_kernel as usize)
+            .ok_or_else(|| SnapshotError::Invalid(format!("unknown force kernel {}", force_kernel)))?;
+        let time_step = reader.f32()?;
+        let max_substeps = reader.u32()?;
+        let seed = reader.u64()?;
+
+        let particles_per_type = (0..n_types).map(|_| reader.u32()).collect::<Result<Vec<_>, _>>()?;
+        if particles_per_type.iter().map(|&count| count as u64).sum::<u64>() != n_particles as u64 {
+            return Err(SnapshotError::Invalid(String::from("per type counts do not add up to the particle count")));
+        }
+        let n_pairs = n_types * n_types;
+        let mut read_table = || (0..n_pairs).map(|_| reader.f32()).collect::<Result<Vec<_>, _>>();
+        let attraction_table = read_table()?;
+        let min_r_table = read_table()?;
+        let max_r_table = read_table()?;
+
+        let colors = create_particle_colors(n_types);
+        let mut particles = Vec::with_capacity(n_particles as usize);
+        for _ in 0..n_particles {
+            let pos = [reader.f32()?, reader.f32()?];
+            let vel = [reader.f32()?, reader.f32()?];
+            let type_idx = reader.u32()?;
+            if type_idx >= n_types {
+                return Err(SnapshotError::Invalid(format!("particle type {} out of range", type_idx)));
+            }
+            particles.push(Particle { pos, vel, color: colors[type_idx as usize], type_idx });
+        }
+
+        let snapshot = Self {
+            n_types,
+            particles_per_type,
+            particle_capacity,
+            attraction_table,
+            min_r_table,
+            max_r_table,
+            min_r,
+            max_r,
+            friction_half_time,
+            speed,
+            boundary,
+            force_kernel,
+            time_step,
+            max_substeps,
+            seed,
+            particles,
+        };
+        snapshot.validate()?;
+        Ok(snapshot)
+    }
+
+    fn validate(&self) -> Result<(), SnapshotError> {
+        for (name, value) in [("time_step", self.time_step), ("friction_half_time", self.friction_half_time)] {
+            if !value.is_finite() || value <= 0.0 {
+                return Err(SnapshotError::Invalid(format!("{} must be positive, got {}", name, value)));
+            }
+        }
+        for (name, value) in [("min_r", self.min_r), ("max_r", self.max_r)] {
+            if !value.is_finite() || value < 0.0 {
+                return Err(SnapshotError::Invalid(format!("{} must not be negative, got {}", name, value)));
+            }
+        }
+        if !self.speed.is_finite() {
+            return Err(SnapshotError::Invalid(format!("speed must be finite, got {}", self.speed)));
+        }
+        if self.max_substeps == 0 {
+            return Err(SnapshotError::Invalid(String::from("max_substeps must be at least 1")));
+        }
+        if self.attraction_table.iter().any(|v| !v.is_finite()) {
+            return Err(SnapshotError::Invalid(String::from("attraction_table contains a non-finite value")));
+        }
+        for (name, table) in [("min_r_table", &self.min_r_table), ("max_r_table", &self.max_r_table)] {
+            if table.iter().any(|v| !v.is_finite() || *v < 0.0) {
+                return Err(SnapshotError::Invalid(format!("{} contains a negative or non-finite value", name)));
+            }
+        }
+        if self.particles.iter().any(|p| p.pos.iter().chain(&p.vel).any(|v| !v.is_finite())) {
+            return Err(SnapshotError::Invalid(String::from("particles contain a non-finite position or velocity")));
+        }
+        Ok(())
+    }
+
+    /// The particles replace the buffer contents on the next frame.
+    pub fn apply(self, settings: &mut UISettings) {
+        settings.num_particle_types = self.n_types;
+        settings.particles_per_type = self.particles_per_type;
+        settings.particle_capacity = aligned_capacity(self.particle_capacity);
+        settings.attraction_table = self.attraction_table;
+        settings.min_r_table = self.min_r_table;
+        settings.max_r_table = self.max_r_table;
+        settings.min_r = self.min_r;
+        settings.max_r = self.max_r;
+        settings.friction_half_time = self.friction_half_time;
+        settings.speed = self.speed;
+        settings.boundary = self.boundary;
+        settings.force_kernel = self.force_kernel;
+        settings.time_step = self.time_step;
+        settings.max_substeps = self.max_substeps;
+        settings.seed = self.seed;
+        settings.active_seed = self.seed;
+        settings.ptype_colors = create_particle_colors(self.n_types);
+        let n_particles = settings.num_particles();
+        settings.reserve_particles(n_particles);
+        settings.loaded_particles = Some(Arc::new(self.particles));
+        settings.particle_count_changed = true;
+    }
+}
+
+/// Little endian encoding shared by the binary file formats.
+pub(super) struct Writer(pub Vec<u8>);
+
+impl Writer {
+    pub fn u32(&mut self, v: u32) {
+        self.0.extend_from_slice(&v.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, v: u64) {
+        self.0.extend_from_slice(&v.to_le_bytes());
+    }
+
+    pub fn f32(&mut self, v: f32) {
+        self.0.extend_from_slice(&v.to_le_bytes());
+    }
+}
+
+pub(super) struct Reader<'a>(pub &'a [u8]);
+
+impl<'a> Reader<'a> {
+    pub fn bytes(&mut self, n: usize) -> std::io::Result<&'a [u8]> {
+        if self.0.len() < n {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        let (bytes, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Ok(bytes)
+    }
+
+    pub fn u32(&mut self) -> std::io::Result<u32> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    pub fn u64(&mut self) -> std::io::Result<u64> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    pub fn f32(&mut self) -> std::io::Result<f32> {
+        Ok(f32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+}
+
+/// Result of the last save, which happens in the render world where the particles are.
+#[derive(Resource, Clone, Default)]
+pub struct SnapshotStatus(pub Arc<Mutex<Option<Result<String, String>>>>);
+
+/// Saves the particles of the previous frame, as shown on screen.
+pub fn save_snapshot(
+    settings: Res<UISettings>,
+    particles_buf: Res<ParticlesBuffer>,
+    render_device: Res<RenderDevice>,
+    snapshot_status: Res<SnapshotStatus>,
+) {
+    if !settings.snapshot_save_requested { return; }
+    // the staging buffer holds the recorded frame, not the particles the settings belong to
+    if settings.playback_frame.is_some() {
+        *snapshot_status.0.lock().unwrap() = Some(Err(String::from("cannot save a snapshot during trajectory playback")));
+        return;
+    }
+
+    let n_particles = settings.num_particles().min(particles_buf.capacity);
+    let particles = if n_particles > 0 { read_staging_particles(&render_device, &particles_buf, n_particles) } else { Vec::new() };
+    let status = match Snapshot::from_settings(&settings, particles).save(&settings.snapshot_path) {
+        Ok(()) => Ok(format!("Saved {}", settings.snapshot_path)),
+        Err(err) => Err(err.to_string()),
+    };
+    *snapshot_status.0.lock().unwrap() = Some(status);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_snapshot() -> Snapshot {
+        let particles = vec![
+            Particle { pos: [0.25, 0.5], vel: [0.01, -0.02], color: [0.0; 3], type_idx: 0 },
+            Particle { pos: [1.5, 0.125], vel: [0.0, 0.0], color: [0.0; 3], type_idx: 0 },
+            Particle { pos: [0.75, 0.999], vel: [-0.5, 0.5], color: [0.0; 3], type_idx: 1 },
+        ];
+        Snapshot {
+            n_types: 2,
+            particles_per_type: vec![2, 1],
+            particle_capacity: 64,
+            attraction_table: vec![1.0, -0.5, 0.25, 0.0],
+            min_r_table: vec![0.3, 0.2, 0.1, 0.3],
+            max_r_table: vec![0.05, 0.1, 0.08, 0.02],
+            min_r: 0.3,
+            max_r: 0.1,
+            friction_half_time: 0.04,
+            speed: 2.0,
+            boundary: BoundaryMode::ALL[1],
+            force_kernel: ForceKernel::ALL[1],
+            time_step: 1.0 / 120.0,
+            max_substeps: 4,
+            seed: u64::MAX - 7,
+            particles,
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let path = std::env::temp_dir().join(format!("particle_life_snapshot_{}.bin", std::process::id()));
+        let saved = test_snapshot();
+        saved.save(&path).unwrap();
+        let loaded = Snapshot::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded.n_types, saved.n_types);
+        assert_eq!(loaded.particles_per_type, saved.particles_per_type);
+        assert_eq!(loaded.particle_capacity, saved.particle_capacity);
+        assert_eq!(loaded.attraction_table, saved.attraction_table);
+        assert_eq!(loaded.min_r_table, saved.min_r_table);
+        assert_eq!(loaded.max_r_table, saved.max_r_table);
+        assert_eq!((loaded.min_r, loaded.max_r), (saved.min_r, saved.max_r));
+        assert_eq!((loaded.friction_half_time, loaded.speed), (saved.friction_half_time, saved.speed));
+        assert_eq!(loaded.boundary, saved.boundary);
+        assert_eq!(loaded.force_kernel, saved.force_kernel);
+        assert_eq!((loaded.time_step, loaded.max_substeps), (saved.time_step, saved.max_substeps));
+        assert_eq!(loaded.seed, saved.seed);
+        let colors = create_particle_colors(saved.n_types);
+        assert_eq!(loaded.particles.len(), saved.particles.len());
+        for (loaded, saved) in loaded.particles.iter().zip(&saved.particles) {
+            assert_eq!((loaded.pos, loaded.vel, loaded.type_idx), (saved.pos, saved.vel, saved.type_idx));
+            assert_eq!(loaded.color, colors[saved.type_idx as usize]);
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_settings() {
+        let path = std::env::temp_dir().join(format!("particle_life_invalid_snapshot_{}.bin", std::process::id()));
+        let mut snapshot = test_snapshot();
+        snapshot.time_step = 0.0;
+        snapshot.save(&path).unwrap();
+        let result = Snapshot::load(&path);
+        fs::remove_file(&path).unwrap();
+
+        assert!(matches!(result, Err(SnapshotError::Invalid(_))));
+    }
+}
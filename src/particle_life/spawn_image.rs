@@ -0,0 +1,123 @@
+use bevy::{prelude::*, asset::LoadState, render::render_resource::TextureFormat};
+use rand::Rng;
+
+use super::{TEXTURE_SIZE, ui::{UISettings, SpawnLayout}};
+
+
+/// Pixels below this brightness never receive particles.
+const MIN_BRIGHTNESS: f32 = 0.05;
+
+/// Bright pixels of an image grouped by the particle type of the closest color.
+pub struct SpawnImage {
+    /// (position in simulation coordinates, brightness) of the pixels of each type.
+    pixels_per_type: Vec<Vec<([f32; 2], f32)>>,
+    /// Side length of a pixel in simulation units.
+    pixel_size: f32,
+}
+
+impl SpawnImage {
+    /// Fits the image into the world, centered, and assigns each pixel the type whose
+    /// displayed color is closest to it.
+    pub fn from_image(image: &Image, ptype_colors: &[[f32; 3]]) -> Result<Self, String> {
+        if !matches!(image.texture_descriptor.format, TextureFormat::Rgba8UnormSrgb | TextureFormat::Rgba8Unorm) {
+            return Err(format!("unsupported image format {:?}, expected 8 bit RGBA", image.texture_descriptor.format));
+        }
+        let width = image.texture_descriptor.size.width;
+        let height = image.texture_descriptor.size.height;
+
+        let extent = Vec2::new(TEXTURE_SIZE.0 as f32 / TEXTURE_SIZE.1 as f32, 1.0);
+        let pixel_size = (extent.x / width as f32).min(extent.y / height as f32);
+        let origin = extent * 0.5 - Vec2::new(width as f32, height as f32) * pixel_size * 0.5;
+
+        // particle colors are linear, compare against them the way they end up on screen
+        let type_colors: Vec<Vec3> = ptype_colors.iter().map(|&[r, g, b]| {
+            let [r, g, b, _] = Color::rgb_linear(r, g, b).as_rgba_f32();
+            Vec3::new(r, g, b)
+        }).collect();
+
+        let mut pixels_per_type = vec![Vec::new(); ptype_colors.len()];
+        for (idx, pixel) in image.data.chunks_exact(4).enumerate() {
+            let color = Vec3::new(pixel[0] as f32, pixel[1] as f32, pixel[2] as f32) / 255.0;
+            let brightness = color.dot(Vec3::new(0.2126, 0.7152, 0.0722)) * pixel[3] as f32 / 255.0;
+            if brightness < MIN_BRIGHTNESS {
+                continue;
+            }
+            let type_idx = type_colors.iter().enumerate()
+                .min_by(|(_, a), (_, b)| a.distance_squared(color).total_cmp(&b.distance_squared(color)))
+                .map(|(i, _)| i)
+                .unwrap_or(0);
+
+            // image rows go top to bottom, the simulation y axis points up
+            let x = (idx as u32 % width) as f32;
+            let y = (height - 1 - idx as u32 / width) as f32;
+            let pos = origin + Vec2::new(x, y) * pixel_size;
+            pixels_per_type[type_idx].push(([pos.x, pos.y], brightness));
+        }
+
+        if pixels_per_type.iter().all(|pixels| pixels.is_empty()) {
+            return Err(String::from("image has no bright pixels"));
+        }
+        Ok(Self { pixels_per_type, pixel_size })
+    }
+
+    /// Splits `n_particles` between the types by their total brightness in the image.
+    pub fn counts(&self, n_particles: u32) -> Vec<u32> {
+        let weights: Vec<f32> = self.pixels_per_type.iter()
+            .map(|pixels| pixels.iter().map(|(_, brightness)| brightness).sum())
+            .collect();
+        let total: f32 = weights.iter().sum();
+        weights.iter().map(|weight| (n_particles as f32 * weight / total).round() as u32).collect()
+    }
+
+    /// Random position of a particle of the given type, brighter pixels being more likely.
+    /// `None` if no pixel in the image maps to the type.
+    pub fn sample(&self, type_idx: u32, rng: &mut impl Rng) -> Option<[f32; 2]> {
+        let pixels = self.pixels_per_type.get(type_idx as usize).filter(|pixels| !pixels.is_empty())?;
+        // rejection sampling, every pixel passed the brightness threshold so this ends quickly
+        loop {
+            let (pos, brightness) = pixels[rng.gen_range(0..pixels.len())];
+            if rng.gen::<f32>() < brightness {
+                return Some([
+                    pos[0] + rng.gen::<f32>() * self.pixel_size,
+                    pos[1] + rng.gen::<f32>() * self.pixel_size,
+                ]);
+            }
+        }
+    }
+}
+
+/// Image requested through the UI, until it has finished loading.
+#[derive(Resource, Default)]
+pub struct SpawnImageLoad(pub Option<Handle<Image>>);
+
+pub fn spawn_image_update(
+    mut spawn_image_load: ResMut<SpawnImageLoad>,
+    mut settings: ResMut<UISettings>,
+    images: Res<Assets<Image>>,
+    asset_server: Res<AssetServer>,
+) {
+    let Some(handle) = &spawn_image_load.0 else { return; };
+    if asset_server.get_load_state(handle) == LoadState::Failed {
+        settings.spawn_image_status = Some(Err(format!("failed to load {}", settings.spawn_image_path)));
+        spawn_image_load.0 = None;
+        return;
+    }
+    let Some(image) = images.get(handle) else { return; };
+
+    let status = match SpawnImage::from_image(image, &settings.ptype_colors) {
+        Ok(spawn_image) => {
+            let n_particles = settings.num_particles();
+            settings.particles_per_type = spawn_image.counts(n_particles);
+            settings.spawn_image = Some(std::sync::Arc::new(spawn_image));
+            settings.spawn_layout = SpawnLayout::Image;
+            let n_particles = settings.num_particles();
+            settings.reserve_particles(n_particles);
+            settings.active_seed = settings.seed;
+            settings.particle_count_changed = true;
+            Ok(format!("Loaded {}", settings.spawn_image_path))
+        }
+        Err(err) => Err(err),
+    };
+    settings.spawn_image_status = Some(status);
+    spawn_image_load.0 = None;
+}
@@ -0,0 +1,96 @@
+use std::collections::VecDeque;
+
+use bevy::prelude::*;
+
+use super::{readback::ParticleReadback, neighbors::{NeighborGrid, world_extent}, ui::UISettings};
+
+
+/// Older samples are dropped, at the default readback interval this is over a minute.
+pub const MAX_STATS_SAMPLES: usize = 600;
+
+/// Metrics of a single particle readback, particles are taken to have unit mass.
+pub struct StatsSample {
+    /// Seconds since startup.
+    pub time: f64,
+    pub kinetic_energy: f32,
+    pub kinetic_energy_per_type: Vec<f32>,
+    pub mean_speed: f32,
+    /// Centers of mass of the types present, averaged on the circle along wrapping axes so
+    /// that a cluster across an edge is not placed in the middle of the world.
+    pub center_of_mass_per_type: Vec<Option<Vec2>>,
+    /// Fraction of particles with another particle within `UISettings::neighbor_radius`.
+    pub neighbor_fraction: f32,
+}
+
+#[derive(Resource, Default)]
+pub struct ParticleStats {
+    pub samples: VecDeque<StatsSample>,
+}
+
+pub fn update_particle_stats(
+    mut stats: ResMut<ParticleStats>,
+    readback: Res<ParticleReadback>,
+    settings: Res<UISettings>,
+    time: Res<Time>,
+) {
+    if !readback.is_changed() || readback.is_added() { return; }
+
+    let particles = &readback.particles;
+    let n_types = settings.num_particle_types as usize;
+    let extent = world_extent();
+    let wrap = settings.boundary.wrap_axes();
+
+    let mut kinetic_energy_per_type = vec![0.0; n_types];
+    let mut total_speed = 0.0;
+    // sums of positions, and of positions mapped onto the unit circle for wrapping axes
+    let mut pos_sums = vec![Vec2::ZERO; n_types];
+    let mut cos_sums = vec![Vec2::ZERO; n_types];
+    let mut sin_sums = vec![Vec2::ZERO; n_types];
+    let mut counts = vec![0u32; n_types];
+    for p in particles {
+        // the readback can lag behind a change of the number of types
+        let Some(count) = counts.get_mut(p.type_idx as usize) else { continue; };
+        *count += 1;
+        let type_idx = p.type_idx as usize;
+        let vel = Vec2::from(p.vel);
+        kinetic_energy_per_type[type_idx] += 0.5 * vel.length_squared();
+        total_speed += vel.length();
+        let pos = Vec2::from(p.pos);
+        let angle = pos / extent * std::f32::consts::TAU;
+        pos_sums[type_idx] += pos;
+        cos_sums[type_idx] += Vec2::new(angle.x.cos(), angle.y.cos());
+        sin_sums[type_idx] += Vec2::new(angle.x.sin(), angle.y.sin());
+    }
+
+    let center_of_mass_per_type = (0..n_types).map(|type_idx| {
+        if counts[type_idx] == 0 { return None; }
+        let mean = pos_sums[type_idx] / counts[type_idx] as f32;
+        let mean_angle = Vec2::new(sin_sums[type_idx].x.atan2(cos_sums[type_idx].x), sin_sums[type_idx].y.atan2(cos_sums[type_idx].y));
+        let turns = mean_angle / std::f32::consts::TAU;
+        let circular_mean = (turns - turns.floor()) * extent;
+        Some(Vec2::select(wrap, circular_mean, mean))
+    }).collect();
+
+    let n_counted = counts.iter().sum::<u32>();
+    let neighbor_fraction = if particles.is_empty() { 0.0 } else {
+        let grid = NeighborGrid::new(particles, settings.neighbor_radius, settings.boundary);
+        let with_neighbors = (0..particles.len()).filter(|&idx| {
+            let mut found = false;
+            grid.for_each_neighbor(particles, idx, |_| found = true);
+            found
+        }).count();
+        with_neighbors as f32 / particles.len() as f32
+    };
+
+    stats.samples.push_back(StatsSample {
+        time: time.elapsed_seconds_f64(),
+        kinetic_energy: kinetic_energy_per_type.iter().sum(),
+        kinetic_energy_per_type,
+        mean_speed: if n_counted > 0 { total_speed / n_counted as f32 } else { 0.0 },
+        center_of_mass_per_type,
+        neighbor_fraction,
+    });
+    while stats.samples.len() > MAX_STATS_SAMPLES {
+        stats.samples.pop_front();
+    }
+}
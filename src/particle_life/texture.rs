@@ -21,7 +21,7 @@ pub fn setup_texture(
         TextureFormat::Rgba8UnormSrgb,
     );
     image.texture_descriptor.usage =
-        TextureUsages::COPY_DST | TextureUsages::STORAGE_BINDING | TextureUsages::TEXTURE_BINDING | TextureUsages::RENDER_ATTACHMENT;
+        TextureUsages::COPY_DST | TextureUsages::COPY_SRC | TextureUsages::STORAGE_BINDING | TextureUsages::TEXTURE_BINDING | TextureUsages::RENDER_ATTACHMENT;
     let image = images.add(image);
 
     let window = window_query.get_single().unwrap();
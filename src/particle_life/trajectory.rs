@@ -0,0 +1,259 @@
+use std::{fmt, fs, path::Path, sync::Arc};
+
+use bevy::{prelude::*, core::FrameCount, render::renderer::RenderQueue};
+
+use super::{buffers::{Particle, ParticlesBuffer, create_particle_colors}, readback::ParticleReadback, snapshot::{Reader, Writer}, ui::UISettings, MAX_PARTICLE_TYPES, MAX_PARTICLE_CAPACITY};
+
+
+pub const DEFAULT_TRAJECTORY_PATH: &str = "trajectories/trajectory.bin";
+
+const MAGIC: &[u8; 8] = b"PLTRAJ\0\0";
+const VERSION: u32 = 1;
+
+
+pub struct TrajectoryFrame {
+    /// App frames since the recording started.
+    pub frame: u32,
+    pub particles: Arc<Vec<Particle>>,
+}
+
+/// Particle readbacks over a time range.
+///
+/// Stored little endian as the magic and version, the number of types and frames, then per
+/// frame its index, particle count and `pos`, `vel` and `type_idx` of every particle.
+pub struct Trajectory {
+    pub n_types: u32,
+    pub frames: Vec<TrajectoryFrame>,
+}
+
+#[derive(Debug)]
+pub enum TrajectoryError {
+    Io(std::io::Error),
+    Invalid(String),
+}
+
+impl fmt::Display for TrajectoryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TrajectoryError::Io(err) => write!(f, "trajectory file error: {}", err),
+            TrajectoryError::Invalid(msg) => write!(f, "invalid trajectory: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for TrajectoryError {}
+
+impl From<std::io::Error> for TrajectoryError {
+    fn from(err: std::io::Error) -> Self {
+        TrajectoryError::Io(err)
+    }
+}
+
+impl Trajectory {
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), TrajectoryError> {
+        let mut writer = Writer(Vec::new());
+        writer.0.extend_from_slice(MAGIC);
+        writer.u32(VERSION);
+        writer.u32(self.n_types);
+        writer.u32(self.frames.len() as u32);
+        for frame in &self.frames {
+            writer.u32(frame.frame);
+            writer.u32(frame.particles.len() as u32);
+            for p in frame.particles.iter() {
+                p.pos.iter().chain(&p.vel).for_each(|&v| writer.f32(v));
+                writer.u32(p.type_idx);
+            }
+        }
+
+        if let Some(dir) = path.as_ref().parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(path, writer.0)?;
+        Ok(())
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, TrajectoryError> {
+        let contents = fs::read(path)?;
+        let mut reader = Reader(&contents);
+        if reader.bytes(MAGIC.len())? != MAGIC {
+            return Err(TrajectoryError::Invalid(String::from("not a trajectory file")));
+        }
+        let version = reader.u32()?;
+        if version != VERSION {
+            return Err(TrajectoryError::Invalid(format!("unsupported version {}", version)));
+        }
+        let n_types = reader.u32()?;
+        if n_types == 0 || n_types > MAX_PARTICLE_TYPES {
+            return Err(TrajectoryError::Invalid(format!("n_types must be between 1 and {}, got {}", MAX_PARTICLE_TYPES, n_types)));
+        }
+        let n_frames = reader.u32()?;
+
+        let colors = create_particle_colors(n_types);
+        let mut frames = Vec::new();
+        for _ in 0..n_frames {
+            let frame = reader.u32()?;
+            let n_particles = reader.u32()?;
+            if n_particles > MAX_PARTICLE_CAPACITY {
+                return Err(TrajectoryError::Invalid(format!("at most {} particles are supported, got {}", MAX_PARTICLE_CAPACITY, n_particles)));
+            }
+            let mut particles = Vec::with_capacity(n_particles as usize);
+            for _ in 0..n_particles {
+                let pos = [reader.f32()?, reader.f32()?];
+                let vel = [reader.f32()?, reader.f32()?];
+                let type_idx = reader.u32()?;
+                if type_idx >= n_types {
+                    return Err(TrajectoryError::Invalid(format!("particle type {} out of range", type_idx)));
+                }
+                particles.push(Particle { pos, vel, color: colors[type_idx as usize], type_idx });
+            }
+            frames.push(TrajectoryFrame { frame, particles: Arc::new(particles) });
+        }
+        if frames.is_empty() {
+            return Err(TrajectoryError::Invalid(String::from("no frames")));
+        }
+
+        Ok(Self { n_types, frames })
+    }
+
+    pub fn last_frame(&self) -> u32 {
+        self.frames.last().map_or(0, |frame| frame.frame)
+    }
+
+    /// Latest recorded frame at or before `frame`.
+    pub fn frame_at(&self, frame: u32) -> &TrajectoryFrame {
+        let idx = self.frames.partition_point(|recorded| recorded.frame <= frame);
+        &self.frames[idx.saturating_sub(1)]
+    }
+}
+
+/// Collects every particle readback while recording, which reads back every frame meanwhile.
+#[derive(Resource, Default)]
+pub struct TrajectoryRecorder {
+    pub recording: Option<Trajectory>,
+    start_frame: u32,
+    /// Readback interval to restore once the recording stops.
+    readback_interval: u32,
+}
+
+impl TrajectoryRecorder {
+    pub fn start(&mut self, settings: &mut UISettings, frame_count: &FrameCount) {
+        self.recording = Some(Trajectory { n_types: settings.num_particle_types, frames: Vec::new() });
+        self.start_frame = frame_count.0;
+        self.readback_interval = settings.readback_interval;
+        settings.readback_interval = 1;
+    }
+
+    pub fn stop(&mut self, settings: &mut UISettings) -> Option<Trajectory> {
+        let mut recording = self.recording.take()?;
+        settings.readback_interval = self.readback_interval;
+        // types may have been added while recording
+        recording.n_types = recording.n_types.max(settings.num_particle_types);
+        Some(recording)
+    }
+}
+
+#[derive(Resource)]
+pub struct TrajectoryPlayer {
+    pub trajectory: Option<Trajectory>,
+    /// Position on the timeline, in recorded app frames.
+    pub playhead: f32,
+    pub playing: bool,
+    /// Recorded frames advanced per app frame.
+    pub speed: f32,
+}
+
+impl Default for TrajectoryPlayer {
+    fn default() -> Self {
+        Self {
+            trajectory: None,
+            playhead: 0.0,
+            playing: false,
+            speed: 1.0,
+        }
+    }
+}
+
+pub fn record_trajectory(
+    mut recorder: ResMut<TrajectoryRecorder>,
+    readback: Res<ParticleReadback>,
+    frame_count: Res<FrameCount>,
+) {
+    if !readback.is_changed() || readback.is_added() { return; }
+    let frame = frame_count.0.wrapping_sub(recorder.start_frame);
+    let Some(recording) = &mut recorder.recording else { return; };
+    recording.frames.push(TrajectoryFrame {
+        frame,
+        particles: Arc::new(readback.particles.clone()),
+    });
+}
+
+/// Moves the playhead and hands the frame under it to the render world.
+pub fn advance_playback(
+    mut player: ResMut<TrajectoryPlayer>,
+    mut settings: ResMut<UISettings>,
+) {
+    let player = &mut *player;
+    let Some(trajectory) = &player.trajectory else {
+        settings.playback_frame = None;
+        return;
+    };
+
+    let last_frame = trajectory.last_frame() as f32;
+    if player.playing {
+        player.playhead += player.speed;
+        if player.playhead >= last_frame {
+            player.playhead = last_frame;
+            player.playing = false;
+        }
+    }
+    player.playhead = player.playhead.clamp(0.0, last_frame);
+    settings.playback_frame = Some(trajectory.frame_at(player.playhead as u32).particles.clone());
+}
+
+/// Uploads the playback frame into the staging buffer, which is drawn while playing back.
+pub fn write_playback_frame(
+    settings: Res<UISettings>,
+    particles_buf: Res<ParticlesBuffer>,
+    render_queue: Res<RenderQueue>,
+) {
+    let Some(frame) = &settings.playback_frame else { return; };
+    let n_particles = frame.len().min(particles_buf.capacity as usize);
+    if n_particles > 0 {
+        render_queue.write_buffer(&particles_buf.staging, 0, bytemuck::cast_slice(&frame[..n_particles]));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let particle = |pos: [f32; 2], type_idx: u32| Particle { pos, vel: [pos[1], -pos[0]], color: [0.0; 3], type_idx };
+        let saved = Trajectory {
+            n_types: 3,
+            frames: vec![
+                TrajectoryFrame { frame: 0, particles: Arc::new(vec![particle([0.1, 0.2], 0), particle([0.3, 0.4], 2)]) },
+                TrajectoryFrame { frame: 1, particles: Arc::new(vec![particle([0.15, 0.25], 0), particle([0.35, 0.45], 2), particle([1.2, 0.9], 1)]) },
+                TrajectoryFrame { frame: 5, particles: Arc::new(Vec::new()) },
+            ],
+        };
+        let path = std::env::temp_dir().join(format!("particle_life_trajectory_{}.bin", std::process::id()));
+        saved.save(&path).unwrap();
+        let loaded = Trajectory::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded.n_types, saved.n_types);
+        assert_eq!(loaded.frames.len(), saved.frames.len());
+        let colors = create_particle_colors(saved.n_types);
+        for (loaded, saved) in loaded.frames.iter().zip(&saved.frames) {
+            assert_eq!(loaded.frame, saved.frame);
+            assert_eq!(loaded.particles.len(), saved.particles.len());
+            for (loaded, saved) in loaded.particles.iter().zip(saved.particles.iter()) {
+                assert_eq!((loaded.pos, loaded.vel, loaded.type_idx), (saved.pos, saved.vel, saved.type_idx));
+                assert_eq!(loaded.color, colors[saved.type_idx as usize]);
+            }
+        }
+        assert_eq!(loaded.frame_at(3).frame, 1);
+    }
+}
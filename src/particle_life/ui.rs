@@ -1,8 +1,13 @@
-use bevy::{prelude::*, core_pipeline::bloom::{BloomSettings, BloomCompositeMode}};
+use std::sync::Arc;
+
+use bevy::{prelude::*, core::FrameCount, core_pipeline::bloom::{BloomSettings, BloomCompositeMode}};
 use bevy_egui::{egui, EguiContexts};
-use rand::{Rng, thread_rng};
+use egui_plot::{Bar, BarChart, Legend, Line, LineStyle, Plot, PlotPoints, VLine, HLine};
+use rand::{Rng, SeedableRng, thread_rng};
+use rand_chacha::ChaCha8Rng;
+use serde::{Deserialize, Serialize};
 
-use super::{INIT_NUM_TYPES, INIT_NUM_PARTICLES_PER_TYPE, MAX_PARTICLE_TYPES, buffers::create_particle_colors, MAX_PARTICLES_PER_TYPE, texture::ParticleLifeOutputImageEntity};
+use super::{INIT_NUM_TYPES, INIT_NUM_PARTICLES_PER_TYPE, MAX_PARTICLE_TYPES, buffers::{Particle, create_particle_colors, aligned_capacity}, INIT_PARTICLE_CAPACITY, INIT_SEED, MAX_PARTICLE_CAPACITY, WORKGROUP_SIZE, texture::ParticleLifeOutputImageEntity, presets::{Preset, DEFAULT_PRESET_PATH}, snapshot::{Snapshot, SnapshotStatus, DEFAULT_SNAPSHOT_PATH}, spawn_image::{SpawnImage, SpawnImageLoad}, trajectory::{Trajectory, TrajectoryRecorder, TrajectoryPlayer, DEFAULT_TRAJECTORY_PATH}, export::{ExportProgress, DEFAULT_EXPORT_DIR}, screenshot::{ScreenshotStatus, DEFAULT_SCREENSHOT_DIR}, stats::{ParticleStats, StatsSample}, clusters::ParticleClusters, TEXTURE_SIZE};
 
 
 #[derive(Resource, Default, PartialEq, Clone)]
@@ -12,22 +17,375 @@ pub enum UIVisibility {
     Hidden,
 }
 
+#[derive(Default, PartialEq, Clone, Copy)]
+pub enum MatrixView {
+    #[default]
+    Attraction,
+    MinRadius,
+    MaxRadius,
+}
+
+/// How particles interact with the edges of the world. The discriminants are shared with
+/// the `BOUNDARY_*` constants in `particle_life.wgsl`.
+#[derive(Default, PartialEq, Clone, Copy, Debug)]
+pub enum BoundaryMode {
+    #[default]
+    Periodic = 0,
+    Reflective = 1,
+    SoftWalls = 2,
+    Clamp = 3,
+    Cylinder = 4,
+    Circle = 5,
+}
+
+impl BoundaryMode {
+    pub const ALL: [BoundaryMode; 6] = [
+        BoundaryMode::Periodic,
+        BoundaryMode::Reflective,
+        BoundaryMode::SoftWalls,
+        BoundaryMode::Clamp,
+        BoundaryMode::Cylinder,
+        BoundaryMode::Circle,
+    ];
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            BoundaryMode::Periodic => "Periodic",
+            BoundaryMode::Reflective => "Reflective Walls",
+            BoundaryMode::SoftWalls => "Soft Walls",
+            BoundaryMode::Clamp => "Clamp",
+            BoundaryMode::Cylinder => "Horizontal Wrap",
+            BoundaryMode::Circle => "Circular Arena",
+        }
+    }
+
+    /// Axes along which the world wraps around, mirrors `wrapAxes` in `particle_life.wgsl`.
+    pub fn wrap_axes(&self) -> BVec2 {
+        let periodic = *self == BoundaryMode::Periodic;
+        BVec2::new(periodic || *self == BoundaryMode::Cylinder, periodic)
+    }
+}
+
+/// Where the particles are placed when they are created.
+#[derive(Default, PartialEq, Clone, Copy, Debug)]
+pub enum SpawnLayout {
+    #[default]
+    Uniform,
+    Clusters,
+    Rings,
+    Spiral,
+    Stripes,
+    Disk,
+    Blob,
+    /// Bright pixels of the loaded spawn image, typed by color.
+    Image,
+}
+
+impl SpawnLayout {
+    pub const ALL: [SpawnLayout; 8] = [
+        SpawnLayout::Uniform,
+        SpawnLayout::Clusters,
+        SpawnLayout::Rings,
+        SpawnLayout::Spiral,
+        SpawnLayout::Stripes,
+        SpawnLayout::Disk,
+        SpawnLayout::Blob,
+        SpawnLayout::Image,
+    ];
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            SpawnLayout::Uniform => "Uniform",
+            SpawnLayout::Clusters => "Cluster Per Type",
+            SpawnLayout::Rings => "Concentric Rings",
+            SpawnLayout::Spiral => "Spiral",
+            SpawnLayout::Stripes => "Stripes By Type",
+            SpawnLayout::Disk => "Central Disk",
+            SpawnLayout::Blob => "Small Blob",
+            SpawnLayout::Image => "Image",
+        }
+    }
+}
+
+/// File the output image is exported to.
+#[derive(Default, PartialEq, Clone, Copy, Debug)]
+pub enum ExportFormat {
+    #[default]
+    PngSequence,
+    /// Uncompressed YUV 4:2:0 video, readable by ffmpeg and most players.
+    Y4m,
+    Gif,
+}
+
+impl ExportFormat {
+    pub const ALL: [ExportFormat; 3] = [
+        ExportFormat::PngSequence,
+        ExportFormat::Y4m,
+        ExportFormat::Gif,
+    ];
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            ExportFormat::PngSequence => "PNG Sequence",
+            ExportFormat::Y4m => "Y4M Video",
+            ExportFormat::Gif => "Animated GIF",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ExportFormat::PngSequence => "png",
+            ExportFormat::Y4m => "y4m",
+            ExportFormat::Gif => "gif",
+        }
+    }
+}
+
+/// Metric plotted over time in the statistics window.
+#[derive(Default, PartialEq, Clone, Copy, Debug)]
+pub enum StatsPlot {
+    #[default]
+    KineticEnergy,
+    MeanSpeed,
+    CenterOfMass,
+    NeighborFraction,
+}
+
+impl StatsPlot {
+    pub const ALL: [StatsPlot; 4] = [
+        StatsPlot::KineticEnergy,
+        StatsPlot::MeanSpeed,
+        StatsPlot::CenterOfMass,
+        StatsPlot::NeighborFraction,
+    ];
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            StatsPlot::KineticEnergy => "Kinetic Energy",
+            StatsPlot::MeanSpeed => "Mean Speed",
+            StatsPlot::CenterOfMass => "Center Of Mass",
+            StatsPlot::NeighborFraction => "Fraction With Neighbors",
+        }
+    }
+}
+
+/// What dragging the mouse over the simulation does.
+#[derive(Default, PartialEq, Clone, Copy, Debug)]
+pub enum BrushTool {
+    #[default]
+    Off,
+    Force,
+    Spawn,
+    Erase,
+}
+
+impl BrushTool {
+    pub const ALL: [BrushTool; 4] = [
+        BrushTool::Off,
+        BrushTool::Force,
+        BrushTool::Spawn,
+        BrushTool::Erase,
+    ];
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            BrushTool::Off => "Off",
+            BrushTool::Force => "Attract / Repel",
+            BrushTool::Spawn => "Spawn",
+            BrushTool::Erase => "Erase",
+        }
+    }
+}
+
+/// Mouse button held over the output image this frame.
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub struct BrushStroke {
+    /// In simulation coordinates.
+    pub pos: [f32; 2],
+    /// Right button, repels with the force brush.
+    pub secondary: bool,
+}
+
+/// Shape of the force between two particles over distance, compiled into the update shader.
+#[derive(Default, PartialEq, Eq, Hash, Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ForceKernel {
+    #[default]
+    Linear,
+    Smooth,
+    LennardJones,
+    Gravity,
+    Rings,
+}
+
+impl ForceKernel {
+    pub const ALL: [ForceKernel; 5] = [
+        ForceKernel::Linear,
+        ForceKernel::Smooth,
+        ForceKernel::LennardJones,
+        ForceKernel::Gravity,
+        ForceKernel::Rings,
+    ];
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            ForceKernel::Linear => "Linear",
+            ForceKernel::Smooth => "Smooth",
+            ForceKernel::LennardJones => "Lennard-Jones",
+            ForceKernel::Gravity => "Gravity",
+            ForceKernel::Rings => "Rings",
+        }
+    }
+
+    /// CPU version of `kernel()` in `particle_life.wgsl`, keep the two in sync.
+    pub fn eval(&self, r: f32, a: f32, min_r: f32) -> f32 {
+        use std::f32::consts::{PI, TAU};
+        const GRAVITY_SOFTENING: f32 = 0.05;
+
+        let repulsion = |r: f32| r / min_r - 1.0;
+        match self {
+            ForceKernel::Linear => {
+                if r < min_r {
+                    repulsion(r)
+                } else if min_r < r && r < 1.0 {
+                    a * (1.0 - (2.0 * r - 1.0 - min_r).abs() / (1.0 - min_r))
+                } else {
+                    0.0
+                }
+            }
+            ForceKernel::Smooth => {
+                if r < min_r {
+                    -0.5 * (1.0 + (PI * r / min_r).cos())
+                } else if min_r < r && r < 1.0 {
+                    a * 0.5 * (1.0 - (TAU * (r - min_r) / (1.0 - min_r)).cos())
+                } else {
+                    0.0
+                }
+            }
+            ForceKernel::LennardJones => {
+                let x6 = (min_r / r.max(0.001)).powf(6.0);
+                (a * x6 - 2.0 * x6 * x6).clamp(-1.0, 1.0) * (1.0 - r)
+            }
+            ForceKernel::Gravity => {
+                if r < min_r {
+                    repulsion(r)
+                } else {
+                    a * GRAVITY_SOFTENING / (r * r + GRAVITY_SOFTENING)
+                }
+            }
+            ForceKernel::Rings => {
+                if r < min_r {
+                    repulsion(r)
+                } else if min_r < r && r < 1.0 {
+                    a * (3.0 * PI * (r - min_r) / (1.0 - min_r)).sin()
+                } else {
+                    0.0
+                }
+            }
+        }
+    }
+
+    pub fn shader_def(&self) -> &'static str {
+        match self {
+            ForceKernel::Linear => "FORCE_KERNEL_LINEAR",
+            ForceKernel::Smooth => "FORCE_KERNEL_SMOOTH",
+            ForceKernel::LennardJones => "FORCE_KERNEL_LENNARD_JONES",
+            ForceKernel::Gravity => "FORCE_KERNEL_GRAVITY",
+            ForceKernel::Rings => "FORCE_KERNEL_RINGS",
+        }
+    }
+}
+
 #[derive(Resource, Clone)]
 pub struct UISettings {
     pub num_particle_types: u32,
-    pub num_particles_per_type: u32,
+    /// Particle count of each type, `num_particle_types` long.
+    pub particles_per_type: Vec<u32>,
+    pub particle_capacity: u32,
 
-    pub attraction_table: [f32; (MAX_PARTICLE_TYPES * MAX_PARTICLE_TYPES) as usize],
-    pub ptype_colors: [[f32; 3]; MAX_PARTICLE_TYPES as usize],
+    /// Per type pair tables, `num_particle_types * num_particle_types` long.
+    pub attraction_table: Vec<f32>,
+    pub min_r_table: Vec<f32>,
+    pub max_r_table: Vec<f32>,
+    pub matrix_view: MatrixView,
+    /// (attracted, attractor) type pair shown in the force curve plot.
+    pub plot_pair: [u32; 2],
+    pub ptype_colors: Vec<[f32; 3]>,
+    /// Seed for the initial positions and the randomized attraction table.
+    pub seed: u64,
+    /// Seed the current particles were created with, `seed` takes effect on the next reset.
+    pub active_seed: u64,
+    /// Takes effect on the next reset or particle count change.
+    pub spawn_layout: SpawnLayout,
+    /// Asset path of the image used by `SpawnLayout::Image`, relative to the `assets` directory.
+    pub spawn_image_path: String,
+    pub spawn_image: Option<Arc<SpawnImage>>,
+    pub spawn_image_status: Option<Result<String, String>>,
 
     pub particle_size: f32,
     pub prev_bloom_settings: Option<BloomSettings>,
 
+    /// Writes every frame of the output image into `export_dir`, as numbered PNGs or a single video.
+    pub frame_export_active: bool,
+    pub export_dir: String,
+    pub export_format: ExportFormat,
+    /// Simulation steps per exported frame, independent of real time.
+    pub export_steps_per_frame: u32,
+    /// Playback rate of exported videos.
+    pub export_fps: u32,
+    /// Length of the export in seconds of video, 0 exports until stopped.
+    pub export_duration: f32,
+    /// Why the last export stopped early, if it failed.
+    pub export_error: Option<String>,
+    pub screenshot_dir: String,
+    /// Width of screenshots, the height follows from the aspect ratio of the output image.
+    pub screenshot_width: u32,
+    /// Set for a single frame, the render world draws the particles offscreen and saves them.
+    pub screenshot_requested: bool,
+    pub screenshot_status: Option<Result<String, String>>,
+
     pub min_r: f32,
     pub max_r: f32,
     pub friction_half_time: f32,
     pub speed: f32,
-    pub wrap: bool,
+    pub boundary: BoundaryMode,
+    pub force_kernel: ForceKernel,
+    pub time_step: f32,
+    pub max_substeps: u32,
+    /// Frames between copies of the particles to `ParticleReadback`, 0 disables them.
+    pub readback_interval: u32,
+    /// Particles closer than this are neighbors in the statistics, and in the same cluster.
+    pub neighbor_radius: f32,
+    pub stats_plot: StatsPlot,
+    /// Smaller connected groups of particles are not counted as clusters.
+    pub min_cluster_size: u32,
+    pub color_by_cluster: bool,
+    /// Colors of the particles of the latest readback by cluster, drawn instead of the type
+    /// colors while coloring by cluster.
+    pub cluster_colors: Option<Arc<Vec<u32>>>,
+
+    pub brush_tool: BrushTool,
+    /// Relative to the height of the world.
+    pub brush_radius: f32,
+    pub brush_strength: f32,
+    /// Type of the particles spawned by the brush.
+    pub brush_type: u32,
+    /// Particles spawned per frame.
+    pub brush_spawn_rate: u32,
+    pub brush_stroke: Option<BrushStroke>,
+
+    pub preset_path: String,
+    pub preset_status: Option<Result<String, String>>,
+    pub snapshot_path: String,
+    pub snapshot_status: Option<Result<String, String>>,
+    /// Set for a single frame, the render world reads back the particles and saves them.
+    pub snapshot_save_requested: bool,
+    /// Particles of a loaded snapshot, set for a single frame together with `particle_count_changed`.
+    pub loaded_particles: Option<Arc<Vec<Particle>>>,
+    pub trajectory_path: String,
+    pub trajectory_status: Option<Result<String, String>>,
+    /// Recorded frame drawn instead of the simulation while a trajectory is played back.
+    pub playback_frame: Option<Arc<Vec<Particle>>>,
 
     pub just_started: bool,
     pub just_reset: bool,
@@ -40,10 +398,21 @@ impl Default for UISettings {
     fn default() -> Self {
         Self {
             num_particle_types: INIT_NUM_TYPES,
-            num_particles_per_type: INIT_NUM_PARTICLES_PER_TYPE,
+            particles_per_type: vec![INIT_NUM_PARTICLES_PER_TYPE; INIT_NUM_TYPES as usize],
+            particle_capacity: INIT_PARTICLE_CAPACITY,
 
-            attraction_table: [0.0; (MAX_PARTICLE_TYPES * MAX_PARTICLE_TYPES) as usize],
-            ptype_colors: [[1.0, 0.25090736, 0.25090742]; MAX_PARTICLE_TYPES as usize],
+            attraction_table: vec![0.0; (INIT_NUM_TYPES * INIT_NUM_TYPES) as usize],
+            min_r_table: vec![0.3; (INIT_NUM_TYPES * INIT_NUM_TYPES) as usize],
+            max_r_table: vec![0.3; (INIT_NUM_TYPES * INIT_NUM_TYPES) as usize],
+            matrix_view: MatrixView::Attraction,
+            plot_pair: [0, 0],
+            ptype_colors: create_particle_colors(INIT_NUM_TYPES),
+            seed: INIT_SEED,
+            active_seed: INIT_SEED,
+            spawn_layout: SpawnLayout::Uniform,
+            spawn_image_path: String::new(),
+            spawn_image: None,
+            spawn_image_status: None,
 
             particle_size: 1.0,
             prev_bloom_settings: Some(BloomSettings {
@@ -53,11 +422,49 @@ impl Default for UISettings {
                 ..default()
             }),
 
+            frame_export_active: false,
+            export_dir: String::from(DEFAULT_EXPORT_DIR),
+            export_format: ExportFormat::PngSequence,
+            export_steps_per_frame: 1,
+            export_fps: 30,
+            export_duration: 0.0,
+            export_error: None,
+            screenshot_dir: String::from(DEFAULT_SCREENSHOT_DIR),
+            screenshot_width: 3840,
+            screenshot_requested: false,
+            screenshot_status: None,
+
             min_r: 0.3,
             max_r: 0.3,
             friction_half_time: 0.1,
             speed: 1.0,
-            wrap: true,
+            boundary: BoundaryMode::Periodic,
+            force_kernel: ForceKernel::Linear,
+            time_step: 1.0 / 60.0,
+            max_substeps: 4,
+            readback_interval: 10,
+            neighbor_radius: 0.03,
+            stats_plot: StatsPlot::KineticEnergy,
+            min_cluster_size: 10,
+            color_by_cluster: false,
+            cluster_colors: None,
+
+            brush_tool: BrushTool::Off,
+            brush_radius: 0.05,
+            brush_strength: 5.0,
+            brush_type: 0,
+            brush_spawn_rate: 4,
+            brush_stroke: None,
+
+            preset_path: String::from(DEFAULT_PRESET_PATH),
+            preset_status: None,
+            snapshot_path: String::from(DEFAULT_SNAPSHOT_PATH),
+            snapshot_status: None,
+            snapshot_save_requested: false,
+            loaded_particles: None,
+            trajectory_path: String::from(DEFAULT_TRAJECTORY_PATH),
+            trajectory_status: None,
+            playback_frame: None,
 
             just_started: false,
             just_reset: false,
@@ -68,6 +475,55 @@ impl Default for UISettings {
     }
 }
 
+impl UISettings {
+    /// Number of live particles, the rest of the capacity is left unused.
+    pub fn num_particles(&self) -> u32 {
+        self.particles_per_type.iter().sum()
+    }
+
+    /// Number of frames the export stops after, if it has a duration.
+    pub fn export_frame_limit(&self) -> Option<u32> {
+        (self.export_duration > 0.0).then(|| ((self.export_duration * self.export_fps as f32).round() as u32).max(1))
+    }
+
+    pub fn screenshot_size(&self) -> (u32, u32) {
+        let height = (self.screenshot_width as u64 * TEXTURE_SIZE.1 as u64 / TEXTURE_SIZE.0 as u64) as u32;
+        (self.screenshot_width, height.max(1))
+    }
+
+    /// The largest interaction radius of any pair of active types.
+    pub fn active_max_r(&self) -> f32 {
+        self.max_r_table.iter().fold(0.0, |acc, r| r.max(acc))
+    }
+
+    /// Grows the particle capacity if it cannot hold `n_particles`.
+    pub fn reserve_particles(&mut self, n_particles: u32) {
+        if n_particles > self.particle_capacity {
+            self.particle_capacity = aligned_capacity(n_particles);
+        }
+    }
+
+    fn randomize_attraction_table(&mut self) {
+        let mut rng = ChaCha8Rng::seed_from_u64(self.seed);
+        for value in self.attraction_table.iter_mut() {
+            *value = rng.gen_range(-1f32..=1f32);
+        }
+    }
+
+    fn set_num_particle_types(&mut self, new_n_types: u32) {
+        let old_n_types = self.num_particle_types;
+        self.num_particle_types = new_n_types;
+        adjust_type_table(&mut self.attraction_table, old_n_types, new_n_types, 0.0);
+        adjust_type_table(&mut self.min_r_table, old_n_types, new_n_types, self.min_r);
+        adjust_type_table(&mut self.max_r_table, old_n_types, new_n_types, self.max_r);
+        self.ptype_colors = create_particle_colors(new_n_types);
+        let new_count = self.particles_per_type.last().copied().unwrap_or(INIT_NUM_PARTICLES_PER_TYPE);
+        self.particles_per_type.resize(new_n_types as usize, new_count);
+        self.reserve_particles(self.num_particles());
+        self.particle_count_changed = true;
+    }
+}
+
 
 pub fn ui_render_update(
     mut commands: Commands,
@@ -134,7 +590,118 @@ pub fn ui_render_update(
             #[allow(unreachable_patterns)]
             _ => ()
         }
+    });
+}
+
+pub fn ui_export_update(
+    mut contexts: EguiContexts,
+    ui_visibility: Res<UIVisibility>,
+    mut settings: ResMut<UISettings>,
+    export_progress: Res<ExportProgress>,
+    screenshot_status: Res<ScreenshotStatus>,
+    keyboard: Res<Input<KeyCode>>,
+) {
+    settings.screenshot_requested = keyboard.just_pressed(KeyCode::F12);
+    if let Some(status) = screenshot_status.0.lock().unwrap().take() {
+        settings.screenshot_status = Some(status);
+    }
+    let (frames_written, export_error) = {
+        let mut state = export_progress.0.lock().unwrap();
+        (state.frames_written, state.error.take())
+    };
+    if let Some(err) = export_error {
+        settings.frame_export_active = false;
+        settings.export_error = Some(err);
+    }
+    if settings.export_frame_limit().is_some_and(|limit| frames_written >= limit) {
+        settings.frame_export_active = false;
+    }
+    if ui_visibility.clone() == UIVisibility::Hidden { return; }
 
+    egui::Window::new("Export").show(contexts.ctx_mut(), |ui| {
+        ui.horizontal(|ui| {
+            ui.label("Export Directory:");
+            ui.add_enabled(!settings.frame_export_active, egui::TextEdit::singleline(&mut settings.export_dir));
+        });
+        ui.add_enabled_ui(!settings.frame_export_active, |ui| {
+            ui.horizontal(|ui| {
+                ui.label("Export Format:");
+                egui::ComboBox::from_id_source("export_format")
+                    .selected_text(settings.export_format.label())
+                    .show_ui(ui, |ui| {
+                        for format in ExportFormat::ALL {
+                            ui.selectable_value(&mut settings.export_format, format, format.label());
+                        }
+                    });
+            });
+            ui.horizontal(|ui| {
+                ui.label("Frame Rate:");
+                ui.add(egui::widgets::DragValue::new(&mut settings.export_fps).clamp_range(1..=120).suffix(" fps"));
+            });
+            ui.horizontal(|ui| {
+                ui.label("Duration:");
+                ui.add(egui::widgets::DragValue::new(&mut settings.export_duration).clamp_range(0f32..=600f32).speed(0.1).suffix(" s"));
+                if settings.export_duration == 0.0 {
+                    ui.label("until stopped");
+                }
+            });
+        });
+        ui.horizontal(|ui| {
+            ui.label("Steps Per Exported Frame:");
+            ui.add(egui::widgets::DragValue::new(&mut settings.export_steps_per_frame).clamp_range(1..=64));
+        });
+        ui.horizontal(|ui| {
+            let button_text = match settings.frame_export_active {
+                true => "Stop Export",
+                false => "Start Export",
+            };
+            if ui.button(button_text).clicked() {
+                settings.frame_export_active = !settings.frame_export_active;
+                if settings.frame_export_active {
+                    settings.running = true;
+                    settings.export_error = None;
+                    // the render world resets it too, but only once the export has been extracted
+                    export_progress.0.lock().unwrap().frames_written = 0;
+                }
+            }
+            if settings.frame_export_active {
+                match settings.export_frame_limit() {
+                    Some(limit) => ui.label(format!("{} / {} frames written", frames_written, limit)),
+                    None => ui.label(format!("{} frames written", frames_written)),
+                };
+            }
+        });
+        if let Some(err) = &settings.export_error {
+            ui.colored_label(egui::Color32::RED, err);
+        }
+
+        ui.separator();
+
+        ui.horizontal(|ui| {
+            ui.label("Screenshot Directory:");
+            ui.text_edit_singleline(&mut settings.screenshot_dir);
+        });
+        ui.horizontal(|ui| {
+            ui.label("Screenshot Resolution:");
+            ui.add(egui::widgets::DragValue::new(&mut settings.screenshot_width).clamp_range(64..=8192).speed(8));
+            ui.label(format!("x {}", settings.screenshot_size().1));
+            for (label, width) in [("1080p", 1920), ("4K", 3840), ("8K", 7680)] {
+                if ui.small_button(label).clicked() {
+                    settings.screenshot_width = width;
+                }
+            }
+        });
+        ui.horizontal(|ui| {
+            if ui.button("Take Screenshot").clicked() {
+                settings.screenshot_requested = true;
+            }
+            ui.label("or press [F12]");
+        });
+        match &settings.screenshot_status {
+            Some(Ok(msg)) => { ui.label(msg); },
+            Some(Err(msg)) => { ui.colored_label(egui::Color32::RED, msg); },
+            None => (),
+        }
     });
 }
 
@@ -142,41 +709,166 @@ pub fn ui_particles_update(
     mut contexts: EguiContexts,
     ui_visibility: Res<UIVisibility>,
     mut settings: ResMut<UISettings>,
+    mut spawn_image_load: ResMut<SpawnImageLoad>,
+    asset_server: Res<AssetServer>,
+    snapshot_status: Res<SnapshotStatus>,
 ) {
     settings.particle_count_changed = false;
+    settings.snapshot_save_requested = false;
+    settings.loaded_particles = None;
+    if let Some(status) = snapshot_status.0.lock().unwrap().take() {
+        settings.snapshot_status = Some(status);
+    }
     if ui_visibility.clone() == UIVisibility::Hidden { return; }
 
     egui::Window::new("Particle Settings").show(contexts.ctx_mut(), |ui| {
+        ui.horizontal(|ui| {
+            ui.label("Preset:");
+            ui.text_edit_singleline(&mut settings.preset_path);
+            if ui.button("Load Preset").clicked() {
+                settings.preset_status = Some(match Preset::load(&settings.preset_path) {
+                    Ok(preset) => {
+                        preset.apply(&mut settings);
+                        Ok(format!("Loaded {}", settings.preset_path))
+                    },
+                    Err(err) => Err(err.to_string()),
+                });
+            }
+            if ui.button("Save Preset").clicked() {
+                settings.preset_status = Some(match Preset::from_settings(&settings).save(&settings.preset_path) {
+                    Ok(()) => Ok(format!("Saved {}", settings.preset_path)),
+                    Err(err) => Err(err.to_string()),
+                });
+            }
+        });
+        match &settings.preset_status {
+            Some(Ok(msg)) => { ui.label(msg); },
+            Some(Err(msg)) => { ui.colored_label(egui::Color32::RED, msg); },
+            None => (),
+        }
+
+        ui.horizontal(|ui| {
+            ui.label("Snapshot:");
+            ui.text_edit_singleline(&mut settings.snapshot_path);
+            if ui.button("Load Snapshot").clicked() {
+                settings.snapshot_status = Some(match Snapshot::load(&settings.snapshot_path) {
+                    Ok(snapshot) => {
+                        snapshot.apply(&mut settings);
+                        Ok(format!("Loaded {}", settings.snapshot_path))
+                    },
+                    Err(err) => Err(err.to_string()),
+                });
+            }
+            if ui.button("Save Snapshot").clicked() {
+                settings.snapshot_save_requested = true;
+                settings.snapshot_status = Some(Ok(format!("Saving {}", settings.snapshot_path)));
+            }
+        });
+        match &settings.snapshot_status {
+            Some(Ok(msg)) => { ui.label(msg); },
+            Some(Err(msg)) => { ui.colored_label(egui::Color32::RED, msg); },
+            None => (),
+        }
+
+        ui.separator();
+
         ui.horizontal(|ui| {
             ui.label("Types:");
             if ui.small_button("-").clicked() && settings.num_particle_types > 1 {
-                let old_n_types = settings.num_particle_types;
-                settings.num_particle_types -= 1;
-                let new_n_types = settings.num_particle_types;
-                adjust_attraction_table(&mut settings.attraction_table, old_n_types, new_n_types);
-                settings.ptype_colors = create_particle_colors(settings.num_particle_types);
-                settings.particle_count_changed = true;
+                let new_n_types = settings.num_particle_types - 1;
+                settings.set_num_particle_types(new_n_types);
             }
             ui.label(format!("{}", settings.num_particle_types));
             if ui.small_button("+").clicked() && settings.num_particle_types < MAX_PARTICLE_TYPES {
-                let old_n_types = settings.num_particle_types;
-                settings.num_particle_types += 1;
-                let new_n_types = settings.num_particle_types;
-                adjust_attraction_table(&mut settings.attraction_table, old_n_types, new_n_types);
-                settings.ptype_colors = create_particle_colors(settings.num_particle_types);
-                settings.particle_count_changed = true;
+                let new_n_types = settings.num_particle_types + 1;
+                settings.set_num_particle_types(new_n_types);
             }
         });
 
         ui.horizontal(|ui| {
             ui.label("Particles Per Type:");
-            let prev_particles_per_type = settings.num_particles_per_type;
-            ui.add(egui::widgets::DragValue::new(&mut settings.num_particles_per_type).clamp_range(0..=MAX_PARTICLES_PER_TYPE));
-            if prev_particles_per_type != settings.num_particles_per_type {
+            let prev_particles_per_type = settings.particles_per_type.iter().copied().max().unwrap_or(0);
+            let mut particles_per_type = prev_particles_per_type;
+            let max_particles_per_type = MAX_PARTICLE_CAPACITY / settings.num_particle_types;
+            ui.add(egui::widgets::DragValue::new(&mut particles_per_type).clamp_range(0..=max_particles_per_type));
+            if prev_particles_per_type != particles_per_type {
+                settings.particles_per_type.fill(particles_per_type);
                 settings.particle_count_changed = true;
             }
         });
 
+        ui.collapsing("Per Type Counts", |ui| {
+            for i in 0..settings.num_particle_types as usize {
+                ui.horizontal(|ui| {
+                    let col = settings.ptype_colors[i];
+                    let colrgb = egui::Color32::from_rgb((col[0] * 255.0) as u8, (col[1] * 255.0) as u8, (col[2] * 255.0) as u8);
+                    egui::color_picker::show_color(ui, colrgb, ui.spacing().interact_size);
+                    let prev_count = settings.particles_per_type[i];
+                    let max_count = MAX_PARTICLE_CAPACITY.saturating_sub(settings.num_particles() - prev_count);
+                    ui.add(egui::widgets::DragValue::new(&mut settings.particles_per_type[i]).clamp_range(0..=max_count));
+                    if prev_count != settings.particles_per_type[i] {
+                        settings.particle_count_changed = true;
+                    }
+                });
+            }
+        });
+
+        if settings.particle_count_changed {
+            let n_particles = settings.num_particles();
+            settings.reserve_particles(n_particles);
+            settings.active_seed = settings.seed;
+        }
+
+        ui.horizontal(|ui| {
+            ui.label("Capacity:");
+            let mut capacity = settings.particle_capacity;
+            let min_capacity = aligned_capacity(settings.num_particles());
+            ui.add(egui::widgets::DragValue::new(&mut capacity).clamp_range(min_capacity..=MAX_PARTICLE_CAPACITY).speed(WORKGROUP_SIZE));
+            if capacity != settings.particle_capacity {
+                settings.particle_capacity = aligned_capacity(capacity);
+                settings.active_seed = settings.seed;
+            }
+        });
+
+        ui.horizontal(|ui| {
+            ui.label("Seed:");
+            ui.add(egui::widgets::DragValue::new(&mut settings.seed));
+            if ui.button("New Seed").clicked() {
+                settings.seed = thread_rng().gen::<u32>() as u64;
+            }
+            ui.label(format!("In Use: {}", settings.active_seed));
+        });
+
+        ui.horizontal(|ui| {
+            ui.label("Spawn Layout:");
+            egui::ComboBox::from_id_source("spawn_layout")
+                .selected_text(settings.spawn_layout.label())
+                .show_ui(ui, |ui| {
+                    for layout in SpawnLayout::ALL {
+                        ui.selectable_value(&mut settings.spawn_layout, layout, layout.label());
+                    }
+                });
+        });
+        ui.horizontal(|ui| {
+            ui.label("Image:");
+            ui.add(egui::TextEdit::singleline(&mut settings.spawn_image_path).hint_text("path relative to assets/"));
+            if ui.add_enabled(!settings.spawn_image_path.is_empty(), egui::Button::new("Load Image")).clicked() {
+                spawn_image_load.0 = Some(asset_server.load(settings.spawn_image_path.clone()));
+                settings.spawn_image_status = Some(Ok(format!("Loading {}", settings.spawn_image_path)));
+            }
+        });
+        match &settings.spawn_image_status {
+            Some(Ok(msg)) => { ui.label(msg); },
+            Some(Err(msg)) => { ui.colored_label(egui::Color32::RED, msg); },
+            None => (),
+        }
+
+        ui.horizontal(|ui| {
+            ui.selectable_value(&mut settings.matrix_view, MatrixView::Attraction, "Attraction");
+            ui.selectable_value(&mut settings.matrix_view, MatrixView::MinRadius, "Repulsor Distance");
+            ui.selectable_value(&mut settings.matrix_view, MatrixView::MaxRadius, "Max Force Distance");
+        });
+
         for i in 0..(settings.num_particle_types + 1) {
             ui.horizontal(|ui| {
                 for j in 0..(settings.num_particle_types + 1) {
@@ -203,21 +895,302 @@ pub fn ui_particles_update(
                     }
                     
                     let idx = ((i - 1) * settings.num_particle_types + (j - 1)) as usize;
-                    ui.add(egui::widgets::DragValue::new(&mut settings.attraction_table[idx])
-                        .clamp_range(-1f32..=1f32).speed(0.05).min_decimals(1));
+                    let widget = match settings.matrix_view {
+                        MatrixView::Attraction => egui::widgets::DragValue::new(&mut settings.attraction_table[idx])
+                            .clamp_range(-1f32..=1f32).speed(0.05).min_decimals(1),
+                        MatrixView::MinRadius => egui::widgets::DragValue::new(&mut settings.min_r_table[idx])
+                            .clamp_range(0f32..=1f32).speed(0.025).min_decimals(2),
+                        MatrixView::MaxRadius => egui::widgets::DragValue::new(&mut settings.max_r_table[idx])
+                            .clamp_range(0f32..=1f32).speed(0.025).min_decimals(2),
+                    };
+                    let response = ui.add(widget);
+                    if response.clicked() || response.dragged() || response.has_focus() {
+                        settings.plot_pair = [i - 1, j - 1];
+                    }
                 }
             });
         }
 
-        if ui.button("Randomize Attraction Table").clicked() {
-            let mut rng = thread_rng();
-            for i in 0..settings.num_particle_types {
-                for j in 0..settings.num_particle_types {
-                    let idx = (i * settings.num_particle_types + j) as usize;
-                    settings.attraction_table[idx] = rng.gen_range(-1f32..=1f32);
+        ui.horizontal(|ui| {
+            if ui.button("Randomize Attraction Table").clicked() {
+                settings.seed = thread_rng().gen::<u32>() as u64;
+                settings.randomize_attraction_table();
+            }
+            if ui.button("Attraction Table From Seed").clicked() {
+                settings.randomize_attraction_table();
+            }
+        });
+    });
+}
+
+pub fn ui_force_plot_update(
+    mut contexts: EguiContexts,
+    ui_visibility: Res<UIVisibility>,
+    mut settings: ResMut<UISettings>,
+) {
+    if ui_visibility.clone() == UIVisibility::Hidden { return; }
+
+    egui::Window::new("Force Curve").show(contexts.ctx_mut(), |ui| {
+        let n_types = settings.num_particle_types;
+        ui.horizontal(|ui| {
+            for (k, label) in ["Attracted:", "Attractor:"].into_iter().enumerate() {
+                ui.label(label);
+                let type_idx = settings.plot_pair[k].min(n_types - 1);
+                let col = settings.ptype_colors[type_idx as usize];
+                let colrgb = egui::Color32::from_rgb((col[0] * 255.0) as u8, (col[1] * 255.0) as u8, (col[2] * 255.0) as u8);
+                egui::color_picker::show_color(ui, colrgb, ui.spacing().interact_size);
+                ui.add(egui::widgets::DragValue::new(&mut settings.plot_pair[k]).clamp_range(0..=n_types - 1));
+            }
+        });
+
+        let [i, j] = settings.plot_pair.map(|type_idx| type_idx.min(n_types - 1));
+        let idx = (i * n_types + j) as usize;
+        let a = settings.attraction_table[idx];
+        let min_r = settings.min_r_table[idx];
+        let max_r = settings.max_r_table[idx];
+
+        // sample a little past the cutoff so the drop to zero is visible
+        const N_SAMPLES: usize = 256;
+        let points: PlotPoints = (0..=N_SAMPLES).map(|s| {
+            let dst = 1.2 * max_r * s as f32 / N_SAMPLES as f32;
+            let force = if dst > 0.0 && dst < max_r { settings.force_kernel.eval(dst / max_r, a, min_r) } else { 0.0 };
+            [dst as f64, force as f64]
+        }).collect();
+
+        Plot::new("force_curve")
+            .view_aspect(2.0)
+            .include_y(-1.0)
+            .include_y(1.0)
+            .include_x(0.0)
+            .allow_drag(false)
+            .allow_zoom(false)
+            .allow_scroll(false)
+            .show(ui, |plot_ui| {
+                plot_ui.hline(HLine::new(0.0).color(egui::Color32::DARK_GRAY));
+                plot_ui.vline(VLine::new(min_r * max_r).color(egui::Color32::DARK_GRAY).name("Repulsor Distance"));
+                plot_ui.vline(VLine::new(max_r).color(egui::Color32::GRAY).name("Max Force Distance"));
+                plot_ui.line(Line::new(points).name("Force"));
+            });
+    });
+}
+
+pub fn ui_trajectory_update(
+    mut contexts: EguiContexts,
+    ui_visibility: Res<UIVisibility>,
+    mut settings: ResMut<UISettings>,
+    mut recorder: ResMut<TrajectoryRecorder>,
+    mut player: ResMut<TrajectoryPlayer>,
+    frame_count: Res<FrameCount>,
+) {
+    if ui_visibility.clone() == UIVisibility::Hidden { return; }
+
+    egui::Window::new("Trajectory").show(contexts.ctx_mut(), |ui| {
+        ui.horizontal(|ui| {
+            ui.label("File:");
+            ui.text_edit_singleline(&mut settings.trajectory_path);
+        });
+        ui.horizontal(|ui| {
+            match recorder.recording.as_ref().map(|recording| recording.frames.len()) {
+                None => {
+                    if ui.button("Start Recording").clicked() {
+                        recorder.start(&mut settings, &frame_count);
+                        settings.trajectory_status = Some(Ok(String::from("Recording")));
+                    }
                 }
+                Some(n_frames) => {
+                    if ui.button("Stop Recording").clicked() {
+                        let recording = recorder.stop(&mut settings).unwrap();
+                        settings.trajectory_status = Some(match recording.save(&settings.trajectory_path) {
+                            Ok(()) => Ok(format!("Saved {} frames to {}", n_frames, settings.trajectory_path)),
+                            Err(err) => Err(err.to_string()),
+                        });
+                    }
+                    ui.label(format!("{} frames", n_frames));
+                }
+            }
+            if ui.button("Load Trajectory").clicked() {
+                settings.trajectory_status = Some(match Trajectory::load(&settings.trajectory_path) {
+                    Ok(trajectory) => {
+                        let max_particles = trajectory.frames.iter().map(|frame| frame.particles.len() as u32).max().unwrap_or(0);
+                        settings.reserve_particles(max_particles);
+                        player.trajectory = Some(trajectory);
+                        player.playhead = 0.0;
+                        player.playing = true;
+                        Ok(format!("Loaded {}", settings.trajectory_path))
+                    },
+                    Err(err) => Err(err.to_string()),
+                });
             }
+        });
+        match &settings.trajectory_status {
+            Some(Ok(msg)) => { ui.label(msg); },
+            Some(Err(msg)) => { ui.colored_label(egui::Color32::RED, msg); },
+            None => (),
         }
+
+        let Some(last_frame) = player.trajectory.as_ref().map(|trajectory| trajectory.last_frame()) else { return; };
+        ui.separator();
+        ui.horizontal(|ui| {
+            let button_text = match player.playing {
+                true => "Pause Playback",
+                false => "Play",
+            };
+            if ui.button(button_text).clicked() {
+                if !player.playing && player.playhead >= last_frame as f32 {
+                    player.playhead = 0.0;
+                }
+                player.playing = !player.playing;
+            }
+            if ui.button("Exit Playback").clicked() {
+                player.trajectory = None;
+                player.playing = false;
+            }
+        });
+        ui.add(egui::Slider::new(&mut player.playhead, 0f32..=last_frame as f32).text("Frame"));
+        ui.horizontal(|ui| {
+            ui.label("Playback Speed:");
+            ui.add(egui::widgets::DragValue::new(&mut player.speed).clamp_range(0.1f32..=16f32).speed(0.1).min_decimals(1));
+        });
+    });
+}
+
+pub fn ui_stats_update(
+    mut contexts: EguiContexts,
+    ui_visibility: Res<UIVisibility>,
+    mut settings: ResMut<UISettings>,
+    mut stats: ResMut<ParticleStats>,
+    clusters: Res<ParticleClusters>,
+) {
+    if ui_visibility.clone() == UIVisibility::Hidden { return; }
+
+    egui::Window::new("Clusters").show(contexts.ctx_mut(), |ui| {
+        if settings.readback_interval == 0 {
+            ui.colored_label(egui::Color32::RED, "CPU readback is disabled, clusters are not updated");
+        }
+        ui.horizontal(|ui| {
+            ui.label("Neighbor Distance:");
+            ui.add(egui::widgets::DragValue::new(&mut settings.neighbor_radius).clamp_range(0.001f32..=0.5f32).speed(0.001).min_decimals(3));
+        });
+        ui.horizontal(|ui| {
+            ui.label("Min Cluster Size:");
+            ui.add(egui::widgets::DragValue::new(&mut settings.min_cluster_size).clamp_range(1..=10000));
+        });
+        ui.checkbox(&mut settings.color_by_cluster, "Color By Cluster");
+
+        let mut sizes: Vec<u32> = clusters.clusters.iter().map(|cluster| cluster.size).collect();
+        sizes.sort_unstable();
+        ui.label(format!("Clusters: {}", clusters.clusters.len()));
+        ui.label(format!("Unclustered Particles: {}", clusters.n_unclustered));
+        if let (Some(smallest), Some(largest)) = (sizes.first(), sizes.last()) {
+            ui.label(format!("Cluster Size: {} smallest, {} median, {} largest", smallest, sizes[sizes.len() / 2], largest));
+        }
+
+        // one bar per cluster, largest first, split into the particles of each type
+        const MAX_PLOTTED_CLUSTERS: usize = 64;
+        let n_types = settings.num_particle_types as usize;
+        let mut charts: Vec<BarChart> = Vec::with_capacity(n_types);
+        for type_idx in 0..n_types {
+            let bars = clusters.clusters.iter().take(MAX_PLOTTED_CLUSTERS).enumerate()
+                .map(|(id, cluster)| Bar::new(id as f64, cluster.type_counts.get(type_idx).copied().unwrap_or(0) as f64).width(0.9))
+                .collect();
+            let col = settings.ptype_colors[type_idx];
+            let chart = BarChart::new(bars)
+                .color(egui::Color32::from_rgb((col[0] * 255.0) as u8, (col[1] * 255.0) as u8, (col[2] * 255.0) as u8))
+                .name(format!("Type {}", type_idx))
+                .stack_on(&charts.iter().collect::<Vec<_>>());
+            charts.push(chart);
+        }
+        Plot::new("cluster_sizes")
+            .view_aspect(2.0)
+            .include_y(0.0)
+            .allow_drag(false)
+            .allow_zoom(false)
+            .allow_scroll(false)
+            .x_axis_label("Cluster")
+            .y_axis_label("Particles")
+            .show(ui, |plot_ui| {
+                for chart in charts {
+                    plot_ui.bar_chart(chart);
+                }
+            });
+    });
+
+    egui::Window::new("Statistics").show(contexts.ctx_mut(), |ui| {
+        if settings.readback_interval == 0 {
+            ui.colored_label(egui::Color32::RED, "CPU readback is disabled, statistics are not updated");
+        }
+        ui.horizontal(|ui| {
+            ui.label("Plot:");
+            egui::ComboBox::from_id_source("stats_plot")
+                .selected_text(settings.stats_plot.label())
+                .show_ui(ui, |ui| {
+                    for plot in StatsPlot::ALL {
+                        ui.selectable_value(&mut settings.stats_plot, plot, plot.label());
+                    }
+                });
+            if ui.button("Clear").clicked() {
+                stats.samples.clear();
+            }
+        });
+        ui.horizontal(|ui| {
+            ui.label("Neighbor Distance:");
+            ui.add(egui::widgets::DragValue::new(&mut settings.neighbor_radius).clamp_range(0.001f32..=0.5f32).speed(0.001).min_decimals(3));
+        });
+
+        let Some(latest) = stats.samples.back() else {
+            ui.label("Waiting for the first readback");
+            return;
+        };
+        ui.label(format!("Kinetic Energy: {:.4}", latest.kinetic_energy));
+        ui.label(format!("Mean Speed: {:.4}", latest.mean_speed));
+        ui.label(format!("Fraction With Neighbors: {:.1}%", latest.neighbor_fraction * 100.0));
+
+        let type_color = |type_idx: usize| {
+            let col = settings.ptype_colors.get(type_idx).copied().unwrap_or([1.0; 3]);
+            egui::Color32::from_rgb((col[0] * 255.0) as u8, (col[1] * 255.0) as u8, (col[2] * 255.0) as u8)
+        };
+        let series = |value: &dyn Fn(&StatsSample) -> Option<f32>| -> PlotPoints {
+            stats.samples.iter()
+                .filter_map(|sample| value(sample).map(|v| [sample.time, v as f64]))
+                .collect()
+        };
+        let n_types = latest.kinetic_energy_per_type.len();
+
+        Plot::new("stats")
+            .view_aspect(2.0)
+            .include_y(0.0)
+            .allow_drag(false)
+            .allow_zoom(false)
+            .allow_scroll(false)
+            .legend(Legend::default())
+            .show(ui, |plot_ui| {
+                match settings.stats_plot {
+                    StatsPlot::KineticEnergy => {
+                        plot_ui.line(Line::new(series(&|sample| Some(sample.kinetic_energy))).color(egui::Color32::WHITE).name("Total"));
+                        for type_idx in 0..n_types {
+                            let points = series(&|sample| sample.kinetic_energy_per_type.get(type_idx).copied());
+                            plot_ui.line(Line::new(points).color(type_color(type_idx)).name(format!("Type {}", type_idx)));
+                        }
+                    }
+                    StatsPlot::MeanSpeed => {
+                        plot_ui.line(Line::new(series(&|sample| Some(sample.mean_speed))).name("Mean Speed"));
+                    }
+                    StatsPlot::CenterOfMass => {
+                        // x solid and y dashed, in the color of the type
+                        for type_idx in 0..n_types {
+                            for (axis, style) in [(0, LineStyle::Solid), (1, LineStyle::dashed_loose())] {
+                                let points = series(&|sample| sample.center_of_mass_per_type.get(type_idx).copied().flatten().map(|com| com[axis]));
+                                let name = format!("Type {} {}", type_idx, ["x", "y"][axis]);
+                                plot_ui.line(Line::new(points).color(type_color(type_idx)).style(style).name(name));
+                            }
+                        }
+                    }
+                    StatsPlot::NeighborFraction => {
+                        plot_ui.hline(HLine::new(1.0).color(egui::Color32::DARK_GRAY));
+                        plot_ui.line(Line::new(series(&|sample| Some(sample.neighbor_fraction))).name("Fraction With Neighbors"));
+                    }
+                }
+            });
     });
 }
 
@@ -227,6 +1200,7 @@ pub fn ui_update(
     keyboard: Res<Input<KeyCode>>,
     time: Res<Time>,
     mut settings: ResMut<UISettings>,
+    recorder: Res<TrajectoryRecorder>,
 ) {
     settings.just_started = false;
     settings.just_reset = false;
@@ -246,11 +1220,21 @@ pub fn ui_update(
 
         ui.horizontal(|ui| {
             ui.label("Repulsor Distance:");
+            let prev_min_r = settings.min_r;
             ui.add(egui::widgets::DragValue::new(&mut settings.min_r).clamp_range(0f32..=1f32).speed(0.025).min_decimals(2));
+            if prev_min_r != settings.min_r {
+                let min_r = settings.min_r;
+                settings.min_r_table.fill(min_r);
+            }
         });
         ui.horizontal(|ui| {
             ui.label("Max Force Distance:");
+            let prev_max_r = settings.max_r;
             ui.add(egui::widgets::DragValue::new(&mut settings.max_r).clamp_range(0f32..=1f32).speed(0.025).min_decimals(2));
+            if prev_max_r != settings.max_r {
+                let max_r = settings.max_r;
+                settings.max_r_table.fill(max_r);
+            }
         });
         ui.horizontal(|ui| {
             ui.label("Friction Half Time:");
@@ -261,12 +1245,85 @@ pub fn ui_update(
             ui.add(egui::widgets::DragValue::new(&mut settings.speed).speed(0.25));
         });
         ui.horizontal(|ui| {
-            ui.label("Wrap:");
-            ui.add(egui::widgets::Checkbox::new(&mut settings.wrap, ""));
+            ui.label("Force Kernel:");
+            egui::ComboBox::from_id_source("force_kernel")
+                .selected_text(settings.force_kernel.label())
+                .show_ui(ui, |ui| {
+                    for kernel in ForceKernel::ALL {
+                        ui.selectable_value(&mut settings.force_kernel, kernel, kernel.label());
+                    }
+                });
+        });
+        ui.horizontal(|ui| {
+            ui.label("Boundary:");
+            egui::ComboBox::from_id_source("boundary")
+                .selected_text(settings.boundary.label())
+                .show_ui(ui, |ui| {
+                    for mode in BoundaryMode::ALL {
+                        ui.selectable_value(&mut settings.boundary, mode, mode.label());
+                    }
+                });
+        });
+        ui.horizontal(|ui| {
+            ui.label("Time Step:");
+            ui.add(egui::widgets::DragValue::new(&mut settings.time_step).clamp_range(0.001f32..=0.1f32).speed(0.001).min_decimals(3));
+        });
+        ui.horizontal(|ui| {
+            ui.label("Max Sub-steps Per Frame:");
+            ui.add(egui::widgets::DragValue::new(&mut settings.max_substeps).clamp_range(1..=16));
+        });
+        ui.horizontal(|ui| {
+            ui.label("CPU Readback Interval (Frames):");
+            // recording a trajectory reads back every frame
+            ui.add_enabled(recorder.recording.is_none(), egui::widgets::DragValue::new(&mut settings.readback_interval).clamp_range(0..=600))
+                .on_hover_text("0 disables the readback");
         });
 
         ui.separator();
 
+        ui.horizontal(|ui| {
+            ui.label("Mouse Brush:");
+            egui::ComboBox::from_id_source("brush_tool")
+                .selected_text(settings.brush_tool.label())
+                .show_ui(ui, |ui| {
+                    for tool in BrushTool::ALL {
+                        ui.selectable_value(&mut settings.brush_tool, tool, tool.label());
+                    }
+                });
+        });
+        if settings.brush_tool != BrushTool::Off {
+            ui.horizontal(|ui| {
+                ui.label("Brush Radius:");
+                ui.add(egui::widgets::DragValue::new(&mut settings.brush_radius).clamp_range(0.005f32..=0.5f32).speed(0.005).min_decimals(3));
+            });
+        }
+        match settings.brush_tool {
+            BrushTool::Force => {
+                ui.horizontal(|ui| {
+                    ui.label("Brush Strength:");
+                    ui.add(egui::widgets::DragValue::new(&mut settings.brush_strength).clamp_range(0f32..=100f32).speed(0.25));
+                });
+                ui.label("Left drag attracts, right drag repels");
+            }
+            BrushTool::Spawn => {
+                ui.horizontal(|ui| {
+                    ui.label("Spawn Type:");
+                    let n_types = settings.num_particle_types;
+                    let col = settings.ptype_colors[settings.brush_type.min(n_types - 1) as usize];
+                    let colrgb = egui::Color32::from_rgb((col[0] * 255.0) as u8, (col[1] * 255.0) as u8, (col[2] * 255.0) as u8);
+                    egui::color_picker::show_color(ui, colrgb, ui.spacing().interact_size);
+                    ui.add(egui::widgets::DragValue::new(&mut settings.brush_type).clamp_range(0..=n_types - 1));
+                });
+                ui.horizontal(|ui| {
+                    ui.label("Particles Per Frame:");
+                    ui.add(egui::widgets::DragValue::new(&mut settings.brush_spawn_rate).clamp_range(1..=256));
+                });
+            }
+            BrushTool::Erase | BrushTool::Off => (),
+        }
+
+        ui.separator();
+
         ui.horizontal(|ui| {
             let button_text = match settings.running {
                 true => "Pause Simulation",
@@ -281,15 +1338,17 @@ pub fn ui_update(
 
             if ui.button("Reset").clicked() {
                 settings.just_reset = true;
+                settings.active_seed = settings.seed;
             }
         });
     });
 }
 
-fn adjust_attraction_table(table: &mut [f32; (MAX_PARTICLE_TYPES * MAX_PARTICLE_TYPES) as usize], old_n_types: u32, new_n_types: u32) {
-    let mut new = [0.0; (MAX_PARTICLE_TYPES * MAX_PARTICLE_TYPES) as usize];
-    for i in 0..old_n_types {
-        for j in 0..old_n_types {
+fn adjust_type_table(table: &mut Vec<f32>, old_n_types: u32, new_n_types: u32, fill: f32) {
+    let mut new = vec![fill; (new_n_types * new_n_types) as usize];
+    let n_kept = old_n_types.min(new_n_types);
+    for i in 0..n_kept {
+        for j in 0..n_kept {
             let old_idx = (i * old_n_types + j) as usize;
             let new_idx = (i * new_n_types + j) as usize;
             let old_val = table[old_idx];